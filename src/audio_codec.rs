@@ -4,26 +4,30 @@ use ort::{Environment, SessionBuilder, Value, Session};
 use ndarray::{Array, CowArray, IxDyn};
 use anyhow::{Result, Context};
 
-pub struct AudioCodec {
+/// An ONNX Runtime session over a codec's decoder graph, mapping a tensor of
+/// codes to a waveform. Shared by the WavTokenizer and DAC codecs.
+pub struct OnnxDecoder {
     session: Session,
-    pub sr: u32,
 }
 
-impl AudioCodec {
-    pub fn new() -> Result<Self> {
+impl OnnxDecoder {
+    /// Loads `models/{file}`. `missing_hint` is appended to the error when
+    /// the file does not exist.
+    pub fn load(file: &str, environment_name: &str, missing_hint: &str) -> Result<Self> {
         let models_dir = "models";
-        let model_path = Path::new(models_dir).join("decoder.onnx");
+        let model_path = Path::new(models_dir).join(file);
 
         if !model_path.exists() {
             anyhow::bail!(
-                "ONNX model not found at {}. Ensure the project was built correctly.", 
-                model_path.display()
+                "ONNX model not found at {}. {}",
+                model_path.display(),
+                missing_hint
             );
         }
 
         // Initialize environment with ONNX Runtime
         let environment = Environment::builder()
-            .with_name("wavtokenizer_environment")
+            .with_name(environment_name)
             .build()
             .context("Failed to initialize ONNX Runtime environment")?;
 
@@ -31,20 +35,16 @@ impl AudioCodec {
         let environment_arc = Arc::new(environment);
         let session = SessionBuilder::new(&environment_arc)?
             .with_model_from_file(&model_path)
-            .context("Failed to load ONNX model")?;
+            .with_context(|| format!("Failed to load ONNX model {}", model_path.display()))?;
 
-        Ok(AudioCodec {
-            session,
-            sr: 24000,
-        })
+        Ok(OnnxDecoder { session })
     }
 
-    pub fn decode(&self, codes: &[i64]) -> Result<Array<f32, IxDyn>> {
-        // Create input tensor with shape [1, codes.length]
-        let shape = [1, codes.len()];
-        let array = Array::from_shape_vec(IxDyn(&shape), codes.to_vec())
+    /// Runs the decoder on `codes` laid out as `shape`.
+    pub fn run(&self, shape: &[usize], codes: Vec<i64>) -> Result<Array<f32, IxDyn>> {
+        let array = Array::from_shape_vec(IxDyn(shape), codes)
             .context("Failed to create input array")?;
-        
+
         // Convert to CowArray for ONNX Runtime
         let cow_array = CowArray::from(array);
 
@@ -60,9 +60,34 @@ impl AudioCodec {
         // Extract waveform from outputs
         let waveform = outputs[0].try_extract::<f32>()
             .context("Failed to extract output waveform")?;
-        
+
         Ok(waveform.view().to_owned())
     }
+}
+
+pub struct AudioCodec {
+    decoder: OnnxDecoder,
+    pub sr: u32,
+}
+
+impl AudioCodec {
+    pub fn new() -> Result<Self> {
+        let decoder = OnnxDecoder::load(
+            "decoder.onnx",
+            "wavtokenizer_environment",
+            "Ensure the project was built correctly.",
+        )?;
+
+        Ok(AudioCodec {
+            decoder,
+            sr: 24000,
+        })
+    }
+
+    pub fn decode(&self, codes: &[i64]) -> Result<Array<f32, IxDyn>> {
+        // Input shape [1, codes.length]
+        self.decoder.run(&[1, codes.len()], codes.to_vec())
+    }
 
     pub fn sample_rate(&self) -> i32 {
         self.sr as i32
@@ -71,4 +96,104 @@ impl AudioCodec {
     pub fn get_sr(&self) -> u32 {
         self.sr
     }
-}
\ No newline at end of file
+}
+
+/// Codes decoded again in front of each chunk so the decoder's receptive
+/// field sees the same history it would in a single pass.
+const LEFT_CONTEXT_CODES: usize = 32;
+
+/// Codes held back at the end of each chunk until more audio arrives, since
+/// the tail of a window is also shaped by what follows it.
+const LOOKAHEAD_CODES: usize = 8;
+
+/// Incrementally decodes a growing code sequence into PCM without seams at
+/// the chunk boundaries.
+pub struct StreamingDecoder<'a> {
+    codec: &'a AudioCodec,
+    codes: Vec<i64>,
+    emitted: usize,
+}
+
+impl<'a> StreamingDecoder<'a> {
+    pub fn new(codec: &'a AudioCodec) -> Self {
+        StreamingDecoder {
+            codec,
+            codes: Vec::new(),
+            emitted: 0,
+        }
+    }
+
+    pub fn push(&mut self, code: i64) {
+        self.codes.push(code);
+    }
+
+    /// Decodes everything received so far except the lookahead tail.
+    pub fn decode_ready(&mut self) -> Result<Vec<f32>> {
+        let end = self.codes.len().saturating_sub(LOOKAHEAD_CODES);
+        self.decode_until(end)
+    }
+
+    /// Decodes all remaining codes.
+    pub fn finish(&mut self) -> Result<Vec<f32>> {
+        self.decode_until(self.codes.len())
+    }
+
+    fn decode_until(&mut self, end: usize) -> Result<Vec<f32>> {
+        if end <= self.emitted {
+            return Ok(Vec::new());
+        }
+
+        let start = self.emitted.saturating_sub(LEFT_CONTEXT_CODES);
+        let window = &self.codes[start..self.codes.len()];
+        let audio = self.codec.decode(window)?;
+        let audio = audio.as_slice()
+            .ok_or_else(|| anyhow::anyhow!("Decoded audio is not contiguous"))?;
+
+        let samples_per_code = audio.len() / window.len();
+        let from = (self.emitted - start) * samples_per_code;
+        let to = if end == self.codes.len() {
+            audio.len()
+        } else {
+            (end - start) * samples_per_code
+        };
+
+        self.emitted = end;
+        Ok(audio[from..to].to_vec())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::default_speakers::DEFAULT_SPEAKERS;
+    use crate::types::Speaker;
+
+    #[test]
+    fn test_streaming_decoder_matches_full_decode() {
+        let codec = AudioCodec::new().unwrap();
+        let speaker: Speaker = serde_json::from_value(DEFAULT_SPEAKERS["en"]["male_1"].clone()).unwrap();
+
+        let all_codes: Vec<i64> = speaker.words.iter()
+            .flat_map(|w| w.codes.iter().map(|&c| c as i64))
+            .collect();
+        let full = codec.decode(&all_codes).unwrap();
+        let full = full.as_slice().unwrap();
+
+        let mut decoder = StreamingDecoder::new(&codec);
+        let mut streamed = Vec::new();
+        for word in &speaker.words {
+            for &code in &word.codes {
+                decoder.push(code as i64);
+            }
+            streamed.extend(decoder.decode_ready().unwrap());
+        }
+        streamed.extend(decoder.finish().unwrap());
+
+        assert_eq!(streamed.len(), full.len());
+        let max_diff = streamed.iter().zip(full)
+            .map(|(a, b)| (a - b).abs())
+            .fold(0.0f32, f32::max);
+        assert!(max_diff < 1e-2, "max sample difference {}", max_diff);
+    }
+}
+
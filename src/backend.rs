@@ -0,0 +1,291 @@
+use anyhow::Result;
+use llama_cpp_2::token::LlamaToken;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use crate::interface::GGUFModelConfig;
+use crate::metadata::ModelMetadata;
+use crate::tokenizer::PromptTokenizer;
+use crate::model::{
+    BatchOutput, BatchSequence, GenerationConfig, SamplingClassifier, StopReason, StopRule, TokenFilter,
+};
+
+/// Tokens produced by a [`LanguageModel`], one per `next()`.
+pub trait TokenSource<'a>: Iterator<Item = Result<LlamaToken>> {
+    /// Restricts the tokens that may be produced from now on.
+    fn set_filter(&mut self, filter: Box<dyn TokenFilter + 'a>);
+
+    /// Ends the stream early when `stop_rule` says so.
+    fn set_stop_rule(&mut self, stop_rule: Box<dyn StopRule + 'a>);
+
+    /// Picks which class settings of `GenerationConfig::class_sampling`
+    /// each token is sampled with.
+    fn set_classifier(&mut self, classifier: Box<dyn SamplingClassifier + 'a>);
+
+    /// Why the stream ended, or `None` while running or after an error.
+    fn stop_reason(&self) -> Option<StopReason>;
+
+    /// Time spent evaluating the prompt before the first token.
+    fn prompt_eval_time(&self) -> Duration;
+
+    /// Flag that stops the stream before its next token when set.
+    fn cancel_flag(&self) -> Arc<AtomicBool>;
+
+    /// Log-probability of the last token under the model's unfiltered
+    /// distribution, if the backend computes one.
+    fn last_logprob(&self) -> Option<f32> {
+        None
+    }
+
+    /// The most likely tokens at the last step with their log-probabilities,
+    /// when the request asked for a trace and the backend computes them.
+    fn last_alternatives(&self) -> &[(LlamaToken, f32)] {
+        &[]
+    }
+}
+
+/// What [`crate::interface::InterfaceGGUF`] needs from a language model
+/// runtime. Token ids are those of `models/tokenizer.json`.
+pub trait LanguageModel: Send + Sync {
+    fn load(config: &GGUFModelConfig) -> Result<Self>
+    where
+        Self: Sized;
+
+    fn generate_stream<'a>(
+        &'a self,
+        input_tokens: &[i32],
+        config: &GenerationConfig,
+    ) -> Result<Box<dyn TokenSource<'a> + 'a>>;
+
+    /// Blocking generation; returns the prompt followed by the new tokens.
+    fn generate(&self, input_tokens: &[i32], config: &GenerationConfig) -> Result<Vec<i32>> {
+        let mut tokens = input_tokens.to_vec();
+        for token in self.generate_stream(input_tokens, config)? {
+            tokens.push(token?.0);
+        }
+        Ok(tokens)
+    }
+
+    /// Generates several independent sequences, returning their outputs in
+    /// order. Backends that cannot decode sequences together run them one
+    /// after another.
+    fn generate_batch<'a>(&'a self, sequences: Vec<BatchSequence<'a>>) -> Result<Vec<BatchOutput>> {
+        sequences.into_iter()
+            .map(|sequence| {
+                let mut tokens = self.generate_stream(&sequence.input_tokens, &sequence.config)?;
+                if let Some(filter) = sequence.filter {
+                    tokens.set_filter(filter);
+                }
+                if let Some(stop_rule) = sequence.stop_rule {
+                    tokens.set_stop_rule(stop_rule);
+                }
+                if let Some(classifier) = sequence.classifier {
+                    tokens.set_classifier(classifier);
+                }
+
+                let started = Instant::now();
+                let mut output = BatchOutput::default();
+                while let Some(token) = tokens.next() {
+                    output.tokens.push(token?.0);
+                    output.logprobs.extend(tokens.last_logprob());
+                }
+                output.stop_reason = tokens.stop_reason();
+                output.prompt_eval_time = tokens.prompt_eval_time();
+                output.generation_time = started.elapsed();
+                Ok(output)
+            })
+            .collect()
+    }
+
+    /// The model file's metadata, used to pick a prompt format and to check
+    /// the vocabulary against `tokenizer.json`.
+    fn metadata(&self) -> Option<&ModelMetadata> {
+        None
+    }
+
+    /// The vocabulary's text for `token`, special tokens included.
+    fn token_piece(&self, _token: i32) -> Option<String> {
+        None
+    }
+
+    /// The model's own vocabulary as a tokenizer, if the backend has one.
+    fn tokenizer(&self) -> Option<Box<dyn PromptTokenizer>> {
+        None
+    }
+
+    /// Context size of the loaded model, i.e. the longest possible sequence.
+    fn context_size(&self) -> Option<usize> {
+        None
+    }
+
+    /// Hint that prompts will often start with `prefix_tokens`. Backends
+    /// without a prompt cache can ignore it.
+    fn cache_prefix(&self, _prefix_tokens: &[i32]) -> Result<()> {
+        Ok(())
+    }
+}
+
+/// Backend that replays a fixed token sequence regardless of the prompt,
+/// for testing the pipeline without a model file. Filters are checked,
+/// not applied: a scripted token the filter rejects is an error.
+pub struct ScriptedModel {
+    tokens: Vec<i32>,
+    metadata: Option<ModelMetadata>,
+}
+
+impl ScriptedModel {
+    pub fn new(tokens: Vec<i32>) -> Self {
+        ScriptedModel { tokens, metadata: None }
+    }
+
+    /// Pretends to be a model file with `metadata`.
+    pub fn with_metadata(mut self, metadata: ModelMetadata) -> Self {
+        self.metadata = Some(metadata);
+        self
+    }
+}
+
+impl LanguageModel for ScriptedModel {
+    fn load(_config: &GGUFModelConfig) -> Result<Self> {
+        Ok(ScriptedModel::new(Vec::new()))
+    }
+
+    fn metadata(&self) -> Option<&ModelMetadata> {
+        self.metadata.as_ref()
+    }
+
+    fn generate_stream<'a>(
+        &'a self,
+        input_tokens: &[i32],
+        config: &GenerationConfig,
+    ) -> Result<Box<dyn TokenSource<'a> + 'a>> {
+        Ok(Box::new(ScriptedStream {
+            tokens: self.tokens.iter(),
+            remaining: config.max_length.saturating_sub(input_tokens.len()),
+            filter: None,
+            stop_rule: None,
+            stop_reason: None,
+            cancelled: config.cancel.flag(),
+            deadline: config.deadline,
+            failed: false,
+        }))
+    }
+}
+
+struct ScriptedStream<'a> {
+    tokens: std::slice::Iter<'a, i32>,
+    remaining: usize,
+    filter: Option<Box<dyn TokenFilter + 'a>>,
+    stop_rule: Option<Box<dyn StopRule + 'a>>,
+    stop_reason: Option<StopReason>,
+    cancelled: Arc<AtomicBool>,
+    deadline: Option<Instant>,
+    failed: bool,
+}
+
+impl<'a> TokenSource<'a> for ScriptedStream<'a> {
+    fn set_filter(&mut self, filter: Box<dyn TokenFilter + 'a>) {
+        self.filter = Some(filter);
+    }
+
+    fn set_stop_rule(&mut self, stop_rule: Box<dyn StopRule + 'a>) {
+        self.stop_rule = Some(stop_rule);
+    }
+
+    /// Scripted tokens are not sampled, so there is nothing to classify.
+    fn set_classifier(&mut self, _classifier: Box<dyn SamplingClassifier + 'a>) {}
+
+    fn stop_reason(&self) -> Option<StopReason> {
+        self.stop_reason
+    }
+
+    fn prompt_eval_time(&self) -> Duration {
+        Duration::ZERO
+    }
+
+    fn cancel_flag(&self) -> Arc<AtomicBool> {
+        self.cancelled.clone()
+    }
+}
+
+impl Iterator for ScriptedStream<'_> {
+    type Item = Result<LlamaToken>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.stop_reason.is_some() || self.failed {
+            return None;
+        }
+        if self.cancelled.load(Ordering::Relaxed) {
+            self.stop_reason = Some(StopReason::Cancelled);
+            return None;
+        }
+        if self.deadline.is_some_and(|deadline| Instant::now() >= deadline) {
+            self.stop_reason = Some(StopReason::TimedOut);
+            return None;
+        }
+        if self.remaining == 0 {
+            self.stop_reason = Some(StopReason::MaxLength);
+            return None;
+        }
+        let Some(&token) = self.tokens.next() else {
+            self.stop_reason = Some(StopReason::EndOfGeneration);
+            return None;
+        };
+        let token = LlamaToken(token);
+        self.remaining -= 1;
+
+        if let Some(filter) = self.filter.as_mut() {
+            if !filter.allows(token) {
+                self.failed = true;
+                return Some(Err(anyhow::anyhow!("Scripted token {} rejected by the filter", token.0)));
+            }
+            filter.accept(token);
+        }
+        if let Some(reason) = self.stop_rule.as_mut().and_then(|rule| rule.check(token)) {
+            self.stop_reason = Some(reason);
+        }
+
+        Some(Ok(token))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Backends and the streams borrowing them, torn down in any order and
+    /// on several threads. Needs no model file or FFI, so it also runs under
+    /// Miri: `cargo +nightly miri test test_create_and_drop_many_backends`.
+    #[test]
+    fn test_create_and_drop_many_backends() {
+        let config = GenerationConfig::default();
+        std::thread::scope(|scope| {
+            for thread in 0..4 {
+                let config = &config;
+                scope.spawn(move || {
+                    let models: Vec<ScriptedModel> = (0..8)
+                        .map(|i| ScriptedModel::new((0..thread + i).collect()))
+                        .collect();
+                    let mut streams: Vec<_> = models.iter()
+                        .map(|model| model.generate_stream(&[0], config).unwrap())
+                        .collect();
+
+                    // Some streams run out, the others are dropped part way
+                    for (i, stream) in streams.iter_mut().enumerate() {
+                        if i % 2 == 0 {
+                            assert_eq!(stream.by_ref().count(), thread as usize + i);
+                            assert_eq!(stream.stop_reason(), Some(StopReason::EndOfGeneration));
+                        } else {
+                            stream.next();
+                        }
+                    }
+                    for stream in streams.into_iter().rev() {
+                        drop(stream);
+                    }
+                    drop(models);
+                });
+            }
+        });
+    }
+}
@@ -0,0 +1,157 @@
+use anyhow::Result;
+use llama_cpp_2::context::params::LlamaContextParams;
+use llama_cpp_2::context::LlamaContext;
+use llama_cpp_2::llama_backend::LlamaBackend;
+use llama_cpp_2::model::LlamaModel;
+use self_cell::self_cell;
+use std::sync::{Arc, Condvar, Mutex};
+use std::time::{Duration, Instant};
+
+use crate::model::{LoraAdapter, StopReason};
+
+/// How often a waiting `checkout` checks whether its request was interrupted.
+const INTERRUPT_POLL_INTERVAL: Duration = Duration::from_millis(50);
+
+self_cell!(
+    /// A context stored together with the model it borrows. The model is
+    /// only freed after the context, and the borrow can never be widened.
+    struct OwnedContext {
+        owner: Arc<LlamaModel>,
+
+        #[covariant]
+        dependent: LlamaContext,
+    }
+);
+
+struct Slot {
+    context: OwnedContext,
+    /// LoRA adapters currently applied to the context
+    lora: Vec<LoraAdapter>,
+}
+
+// SAFETY: a llama context may move between threads as long as only one
+// thread uses it at a time, which checkout/checkin guarantees. The model
+// itself is Send + Sync.
+unsafe impl Send for Slot {}
+
+/// Fixed set of contexts over one loaded model, each with its own KV cache.
+/// Callers check a context out for the duration of a generation and it is
+/// returned to the pool when the guard drops.
+pub struct ContextPool {
+    idle: Mutex<Vec<Slot>>,
+    returned: Condvar,
+    size: usize,
+    timeout: Option<Duration>,
+}
+
+impl ContextPool {
+    pub fn new(
+        model: &Arc<LlamaModel>,
+        backend: &LlamaBackend,
+        params: &LlamaContextParams,
+        size: usize,
+    ) -> Result<Self> {
+        if size == 0 {
+            return Err(anyhow::anyhow!("At least one context is required"));
+        }
+
+        let mut idle = Vec::with_capacity(size);
+        for _ in 0..size {
+            let context = OwnedContext::try_new(model.clone(), |model| {
+                model.new_context(backend, params.clone())
+            })?;
+            idle.push(Slot { context, lora: Vec::new() });
+        }
+
+        Ok(ContextPool {
+            idle: Mutex::new(idle),
+            returned: Condvar::new(),
+            size,
+            timeout: None,
+        })
+    }
+
+    /// How long `checkout` waits for a busy pool; `None` waits forever.
+    pub fn set_timeout(&mut self, timeout: Option<Duration>) {
+        self.timeout = timeout;
+    }
+
+    pub fn size(&self) -> usize {
+        self.size
+    }
+
+    /// Checks out an idle context, waiting for one to be returned when all
+    /// are busy. `interrupted` is polled while waiting, so a request that is
+    /// cancelled or past its deadline gives up without waiting out the pool
+    /// timeout.
+    pub fn checkout(&self, interrupted: impl Fn() -> Option<StopReason>) -> Result<PooledContext<'_>> {
+        let deadline = self.timeout.map(|t| Instant::now() + t);
+        let mut idle = self.idle.lock().unwrap();
+
+        loop {
+            if let Some(slot) = idle.pop() {
+                return Ok(PooledContext { pool: self, slot: Some(slot) });
+            }
+            if let Some(reason) = interrupted() {
+                return Err(anyhow::anyhow!(
+                    "{:?} while waiting for one of {} busy contexts",
+                    reason,
+                    self.size
+                ));
+            }
+
+            let mut wait = INTERRUPT_POLL_INTERVAL;
+            if let Some(deadline) = deadline {
+                let now = Instant::now();
+                if now >= deadline {
+                    return Err(anyhow::anyhow!(
+                        "Timed out after {:?} waiting for one of {} busy contexts",
+                        self.timeout.unwrap(),
+                        self.size
+                    ));
+                }
+                wait = wait.min(deadline - now);
+            }
+            idle = self.returned.wait_timeout(idle, wait).unwrap().0;
+        }
+    }
+
+    fn checkin(&self, slot: Slot) {
+        self.idle.lock().unwrap().push(slot);
+        self.returned.notify_one();
+    }
+}
+
+pub struct PooledContext<'a> {
+    pool: &'a ContextPool,
+    slot: Option<Slot>,
+}
+
+impl PooledContext<'_> {
+    /// Runs `f` with shared access to the context.
+    pub fn with<R>(&self, f: impl FnOnce(&LlamaContext<'_>) -> R) -> R {
+        f(self.slot.as_ref().unwrap().context.borrow_dependent())
+    }
+
+    /// Runs `f` with exclusive access to the context.
+    pub fn with_mut<R>(&mut self, f: impl FnOnce(&mut LlamaContext<'_>) -> R) -> R {
+        self.slot.as_mut().unwrap().context.with_dependent_mut(|_, context| f(context))
+    }
+
+    /// LoRA adapters applied to the context, as last recorded by `set_lora`.
+    pub fn lora(&self) -> &[LoraAdapter] {
+        &self.slot.as_ref().unwrap().lora
+    }
+
+    pub fn set_lora(&mut self, adapters: Vec<LoraAdapter>) {
+        self.slot.as_mut().unwrap().lora = adapters;
+    }
+}
+
+impl Drop for PooledContext<'_> {
+    fn drop(&mut self) {
+        if let Some(slot) = self.slot.take() {
+            self.pool.checkin(slot);
+        }
+    }
+}
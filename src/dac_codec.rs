@@ -0,0 +1,40 @@
+use anyhow::Result;
+use ndarray::{Array, IxDyn};
+
+use crate::audio_codec::OnnxDecoder;
+
+/// ONNX decoder for the two-codebook DAC codec used by OuteTTS 1.0.
+pub struct DacCodec {
+    decoder: OnnxDecoder,
+    pub sr: u32,
+}
+
+impl DacCodec {
+    pub fn new() -> Result<Self> {
+        let decoder = OnnxDecoder::load(
+            "dac_decoder.onnx",
+            "dac_environment",
+            "OuteTTS 1.0 models need the DAC ONNX decoder in the models directory.",
+        )?;
+
+        Ok(DacCodec {
+            decoder,
+            sr: 24000,
+        })
+    }
+
+    /// Decodes one frame per `(c1[i], c2[i])` pair into a waveform.
+    pub fn decode(&self, c1: &[i64], c2: &[i64]) -> Result<Array<f32, IxDyn>> {
+        if c1.len() != c2.len() {
+            anyhow::bail!("Codebook lengths differ: {} vs {}", c1.len(), c2.len());
+        }
+
+        // Input shape [1, n_codebooks, frames]
+        let codes: Vec<i64> = c1.iter().chain(c2).copied().collect();
+        self.decoder.run(&[1, 2, c1.len()], codes)
+    }
+
+    pub fn get_sr(&self) -> u32 {
+        self.sr
+    }
+}
@@ -0,0 +1,214 @@
+use std::collections::HashMap;
+use std::path::Path;
+use serde::{Serialize, Deserialize};
+use anyhow::Result;
+
+use crate::prompt_format::PromptVersion;
+use crate::tokenizer::{HfTokenizer, PromptTokenizer};
+
+/// Codes per codebook in the OuteTTS 1.0 vocabulary.
+const CODEBOOK_SIZE: usize = 1024;
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct AudioFeatures {
+    pub energy: i32,
+    pub spectral_centroid: i32,
+    pub pitch: i32,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DacWord {
+    pub word: String,
+    pub duration: f64,
+    pub c1: Vec<i32>,
+    pub c2: Vec<i32>,
+    #[serde(default)]
+    pub features: AudioFeatures,
+}
+
+/// Speaker profile in the OuteTTS 1.0 JSON schema.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DacSpeaker {
+    pub text: String,
+    pub words: Vec<DacWord>,
+    #[serde(default)]
+    pub global_features: AudioFeatures,
+    #[serde(default)]
+    pub language: String,
+}
+
+/// Prompt builder and output parser for OuteTTS 1.0:
+///
+/// ```text
+/// <|im_start|>
+/// <|global_features_start|><|energy_e|><|spectral_centroid_s|><|pitch_p|><|global_features_end|>
+/// <|text_start|>the<|space|>words<|text_end|>
+/// <|audio_start|>
+/// <|word_start|>the<|features|><|t_0.08|><|energy_e|>…<|code|><|c1_x|><|c2_y|>…<|word_end|>
+/// ```
+pub struct DacPromptProcessor {
+    pub tokenizer: Box<dyn PromptTokenizer>,
+    c1_tokens: HashMap<i64, i64>,
+    c2_tokens: HashMap<i64, i64>,
+}
+
+impl DacPromptProcessor {
+    pub fn new() -> Result<Self> {
+        let tokenizer_path = Path::new("models").join("tokenizer-1.0.json");
+        if !tokenizer_path.exists() {
+            anyhow::bail!(
+                "OuteTTS 1.0 tokenizer not found at {}",
+                tokenizer_path.display()
+            );
+        }
+        Self::with_tokenizer(Box::new(HfTokenizer::from_file(&tokenizer_path)?))
+    }
+
+    /// Builds prompts with `tokenizer` instead of `models/tokenizer-1.0.json`.
+    pub fn with_tokenizer(tokenizer: Box<dyn PromptTokenizer>) -> Result<Self> {
+        let mut processor = DacPromptProcessor {
+            tokenizer,
+            c1_tokens: HashMap::new(),
+            c2_tokens: HashMap::new(),
+        };
+        processor.c1_tokens = processor.codebook_map("c1")?;
+        processor.c2_tokens = processor.codebook_map("c2")?;
+        Ok(processor)
+    }
+
+    fn codebook_map(&self, codebook: &str) -> Result<HashMap<i64, i64>> {
+        let mut map = HashMap::new();
+        for i in 0..CODEBOOK_SIZE {
+            let token = self.single_token(&format!("<|{}_{}|>", codebook, i))?;
+            map.insert(token, i as i64);
+        }
+        Ok(map)
+    }
+
+    /// Id of a token that must encode to exactly one id.
+    pub fn single_token(&self, piece: &str) -> Result<i64> {
+        match self.encode_prompt(piece)?.as_slice() {
+            [id] => Ok(*id),
+            _ => Err(anyhow::anyhow!("{} is not a single token in the OuteTTS 1.0 vocabulary", piece)),
+        }
+    }
+
+    pub fn process_text(&self, text: &str) -> Vec<String> {
+        // 1.0 keeps punctuation in the text, the same way 0.3 does
+        PromptVersion::V0_3.format()
+            .map(|format| format.sanitize_words(text))
+            .unwrap_or_default()
+    }
+
+    fn features(features: &AudioFeatures) -> String {
+        format!(
+            "<|energy_{}|><|spectral_centroid_{}|><|pitch_{}|>",
+            features.energy, features.spectral_centroid, features.pitch
+        )
+    }
+
+    pub(crate) fn word_block(word: &DacWord) -> String {
+        let codes: String = word.c1.iter().zip(&word.c2)
+            .map(|(c1, c2)| format!("<|c1_{}|><|c2_{}|>", c1, c2))
+            .collect();
+        format!(
+            "<|word_start|>{}<|features|><|t_{:.2}|>{}<|code|>{}<|word_end|>",
+            word.word,
+            word.duration,
+            Self::features(&word.features),
+            codes
+        )
+    }
+
+    pub fn get_completion_prompt(&self, text: &str, speaker: Option<&DacSpeaker>) -> String {
+        let mut words = Vec::new();
+        if let Some(speaker) = speaker {
+            words.extend(self.process_text(&speaker.text));
+        }
+        words.extend(self.process_text(text));
+
+        let global = speaker.map(|s| s.global_features.clone()).unwrap_or_default();
+        let mut prompt = format!(
+            "<|im_start|>\n<|global_features_start|>{}<|global_features_end|>\n<|text_start|>{}<|text_end|>\n<|audio_start|>\n",
+            Self::features(&global),
+            words.join("<|space|>")
+        );
+
+        if let Some(speaker) = speaker {
+            for word in &speaker.words {
+                prompt.push_str(&Self::word_block(word));
+                prompt.push('\n');
+            }
+        }
+
+        prompt
+    }
+
+    /// De-interleaves generated tokens into the two codebooks. A `c1` code
+    /// without the `c2` code that should follow it is dropped, so both
+    /// streams always have the same length.
+    pub fn extract_codes(&self, tokens: &[i64]) -> (Vec<i64>, Vec<i64>) {
+        let mut c1 = Vec::new();
+        let mut c2 = Vec::new();
+        let mut pending = None;
+
+        for token in tokens {
+            if let Some(&code) = self.c1_tokens.get(token) {
+                pending = Some(code);
+            } else if let Some(&code) = self.c2_tokens.get(token) {
+                if let Some(first) = pending.take() {
+                    c1.push(first);
+                    c2.push(code);
+                }
+            } else {
+                pending = None;
+            }
+        }
+
+        (c1, c2)
+    }
+
+    /// The structural markers and codebook tokens with their
+    /// ids, for checking a model's vocabulary.
+    pub fn vocab_tokens(&self) -> Result<Vec<(String, i64)>> {
+        let markers = [
+            "<|im_start|>", "<|im_end|>", "<|text_start|>", "<|text_end|>",
+            "<|audio_start|>", "<|audio_end|>", "<|word_start|>", "<|word_end|>",
+            "<|features|>", "<|code|>", "<|space|>",
+            "<|global_features_start|>", "<|global_features_end|>",
+        ];
+        let codes = ["c1", "c2"].into_iter()
+            .flat_map(|codebook| (0..CODEBOOK_SIZE).map(move |i| format!("<|{}_{}|>", codebook, i)));
+
+        markers.into_iter().map(str::to_string).chain(codes)
+            .map(|piece| {
+                let id = self.single_token(&piece)?;
+                Ok((piece, id))
+            })
+            .collect()
+    }
+
+    pub fn encode_prompt(&self, prompt: &str) -> Result<Vec<i64>> {
+        self.tokenizer.encode(prompt)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_speaker_schema() {
+        let speaker: DacSpeaker = serde_json::from_str(r#"{
+            "text": "Hi.",
+            "words": [{"word": "hi.", "duration": 0.2, "c1": [1, 2], "c2": [3, 4],
+                       "features": {"energy": 10, "spectral_centroid": 20, "pitch": 30}}],
+            "global_features": {"energy": 1, "spectral_centroid": 2, "pitch": 3}
+        }"#).unwrap();
+
+        assert_eq!(
+            DacPromptProcessor::word_block(&speaker.words[0]),
+            "<|word_start|>hi.<|features|><|t_0.20|><|energy_10|><|spectral_centroid_20|><|pitch_30|><|code|><|c1_1|><|c2_3|><|c1_2|><|c2_4|><|word_end|>"
+        );
+    }
+}
@@ -0,0 +1,420 @@
+use llama_cpp_2::token::LlamaToken;
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use crate::model::{SamplingClass, SamplingClassifier, StopReason, StopRule, TokenFilter};
+use crate::prompt_processor::PromptProcessor;
+
+/// How strictly generation follows the OuteTTS audio layout.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+pub enum ConstraintMode {
+    /// Sample from the full vocabulary.
+    #[default]
+    Off,
+    /// Only allow tokens that keep the word/time/codes layout well formed.
+    Layout,
+    /// As `Layout`, and force the word text to the next input word.
+    Words,
+}
+
+impl std::str::FromStr for ConstraintMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "off" => Ok(ConstraintMode::Off),
+            "layout" => Ok(ConstraintMode::Layout),
+            "words" => Ok(ConstraintMode::Words),
+            _ => Err(format!("Unknown constraint mode '{}', expected off, layout or words", s)),
+        }
+    }
+}
+
+/// Role a vocabulary entry can play in the audio section of a prompt.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TokenClass {
+    /// Lowercase letters that can spell a word.
+    Text,
+    /// Punctuation token written after a word (OuteTTS 0.3).
+    Punctuation,
+    /// `<|t_x.xx|>`
+    Time,
+    CodeStart,
+    /// `<|n|>` audio code
+    AudioCode,
+    CodeEnd,
+    Newline,
+    AudioEnd,
+    /// End of sequence.
+    End,
+    /// Anything else; never valid inside the audio section.
+    Other,
+}
+
+/// Class of every token id in the vocabulary.
+pub struct TokenClasses {
+    classes: Vec<TokenClass>,
+    /// Seconds spelled by each time token
+    times: HashMap<i32, f32>,
+}
+
+impl TokenClasses {
+    pub fn new(processor: &PromptProcessor) -> anyhow::Result<Self> {
+        let vocab = processor.tokenizer.vocab();
+        let size = vocab.values().copied().max().map_or(0, |id| id as usize + 1);
+        let mut classes = vec![TokenClass::Other; size];
+        let mut times = HashMap::new();
+
+        let time = Regex::new(r"^<\|t_\d+\.\d{2}\|>$").unwrap();
+        let text = Regex::new(r"^[a-z]+$").unwrap();
+        for (piece, &id) in vocab.iter() {
+            if time.is_match(piece) {
+                classes[id as usize] = TokenClass::Time;
+                if let Ok(seconds) = piece[4..piece.len() - 2].parse() {
+                    times.insert(id as i32, seconds);
+                }
+            } else if processor.audio_code(id).is_some() {
+                classes[id as usize] = TokenClass::AudioCode;
+            } else if text.is_match(piece) {
+                classes[id as usize] = TokenClass::Text;
+            }
+        }
+
+        for piece in processor.format().punctuation_tokens() {
+            if let Some(&id) = vocab.get(piece) {
+                classes[id as usize] = TokenClass::Punctuation;
+            }
+        }
+        for (name, class) in [
+            ("code_start", TokenClass::CodeStart),
+            ("code_end", TokenClass::CodeEnd),
+            ("audio_end", TokenClass::AudioEnd),
+        ] {
+            classes[processor.special_token_id(name)? as usize] = class;
+        }
+        for id in processor.encode_prompt("\n")? {
+            classes[id as usize] = TokenClass::Newline;
+        }
+        for piece in ["<|im_end|>", "<|endoftext|>"] {
+            if let Some(&id) = vocab.get(piece) {
+                classes[id as usize] = TokenClass::End;
+            }
+        }
+
+        Ok(TokenClasses { classes, times })
+    }
+
+    pub fn get(&self, token: LlamaToken) -> TokenClass {
+        self.classes.get(token.0 as usize).copied().unwrap_or(TokenClass::Other)
+    }
+
+    /// Duration spelled by a time token such as `<|t_0.32|>`.
+    pub fn time(&self, token: LlamaToken) -> Option<f32> {
+        self.times.get(&token.0).copied()
+    }
+}
+
+/// Position within the audio section.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum GrammarState {
+    /// Spelling a word; `tokens` text tokens emitted so far.
+    Word { tokens: usize },
+    /// After the time token, waiting for `<|code_start|>`.
+    Time,
+    /// Inside `<|code_start|>…<|code_end|>` with `count` codes so far.
+    Codes { count: usize },
+    /// After `<|code_end|>`: a newline starts the next word.
+    WordEnd,
+    /// After `<|audio_end|>`.
+    Done,
+}
+
+impl GrammarState {
+    /// The state after a token of `class`; tokens that don't fit the layout
+    /// leave it unchanged.
+    fn advance(self, class: TokenClass) -> GrammarState {
+        match (self, class) {
+            (_, TokenClass::AudioEnd) => GrammarState::Done,
+            (GrammarState::Word { tokens }, TokenClass::Text | TokenClass::Punctuation) => {
+                GrammarState::Word { tokens: tokens + 1 }
+            }
+            (GrammarState::Word { .. }, TokenClass::Time) => GrammarState::Time,
+            (GrammarState::Time, TokenClass::CodeStart) => GrammarState::Codes { count: 0 },
+            (GrammarState::Codes { count }, TokenClass::AudioCode) => GrammarState::Codes { count: count + 1 },
+            (GrammarState::Codes { .. }, TokenClass::CodeEnd) => GrammarState::WordEnd,
+            (GrammarState::WordEnd, TokenClass::Newline) => GrammarState::Word { tokens: 0 },
+            (state, _) => state,
+        }
+    }
+}
+
+/// Token filter that enforces `word <|t_x.xx|> <|code_start|> codes
+/// <|code_end|>` blocks separated by newlines and closed by `<|audio_end|>`.
+pub struct AudioGrammar {
+    classes: Arc<TokenClasses>,
+    state: GrammarState,
+    /// Token ids of each word to force, for `ConstraintMode::Words`
+    words: Option<Vec<Vec<i32>>>,
+    word_index: usize,
+}
+
+impl AudioGrammar {
+    pub fn new(classes: Arc<TokenClasses>, initial: GrammarState, words: Option<Vec<Vec<i32>>>) -> Self {
+        AudioGrammar {
+            classes,
+            state: initial,
+            words,
+            word_index: 0,
+        }
+    }
+
+    pub fn state(&self) -> GrammarState {
+        self.state
+    }
+
+    fn allows_in_word(&self, token: LlamaToken, class: TokenClass, tokens: usize) -> bool {
+        let Some(words) = &self.words else {
+            return match class {
+                TokenClass::Text => true,
+                TokenClass::Punctuation | TokenClass::Time => tokens > 0,
+                TokenClass::AudioEnd => tokens == 0,
+                _ => false,
+            };
+        };
+
+        match words.get(self.word_index) {
+            None => class == TokenClass::AudioEnd,
+            Some(word) if tokens < word.len() => token.0 == word[tokens],
+            Some(_) => class == TokenClass::Time,
+        }
+    }
+}
+
+impl TokenFilter for AudioGrammar {
+    fn allows(&self, token: LlamaToken) -> bool {
+        let class = self.classes.get(token);
+        match self.state {
+            GrammarState::Word { tokens } => self.allows_in_word(token, class, tokens),
+            GrammarState::Time => class == TokenClass::CodeStart,
+            GrammarState::Codes { count } => {
+                class == TokenClass::AudioCode || (class == TokenClass::CodeEnd && count > 0)
+            }
+            GrammarState::WordEnd => matches!(class, TokenClass::Newline | TokenClass::AudioEnd),
+            GrammarState::Done => class == TokenClass::End,
+        }
+    }
+
+    fn accept(&mut self, token: LlamaToken) {
+        let class = self.classes.get(token);
+        if matches!((self.state, class), (GrammarState::Codes { .. }, TokenClass::CodeEnd)) {
+            self.word_index += 1;
+        }
+        self.state = self.state.advance(class);
+    }
+}
+
+/// Follows the audio layout to tell which [`SamplingClass`] the next token
+/// falls in. Where the layout allows more than one (a time token or more
+/// text after a word's first token, `<|code_end|>` or another code), the
+/// most likely candidate decides.
+pub struct LayoutClassifier {
+    classes: Arc<TokenClasses>,
+    state: GrammarState,
+}
+
+impl LayoutClassifier {
+    pub fn new(classes: Arc<TokenClasses>, initial: GrammarState) -> Self {
+        LayoutClassifier { classes, state: initial }
+    }
+}
+
+impl SamplingClassifier for LayoutClassifier {
+    fn fixed_class(&self) -> Option<SamplingClass> {
+        match self.state {
+            GrammarState::Time | GrammarState::WordEnd | GrammarState::Done => Some(SamplingClass::Structural),
+            GrammarState::Word { .. } | GrammarState::Codes { .. } => None,
+        }
+    }
+
+    fn next_class(&self, top: LlamaToken) -> SamplingClass {
+        let top = self.classes.get(top);
+        match self.state {
+            GrammarState::Word { .. } => match top {
+                TokenClass::Time => SamplingClass::Time,
+                TokenClass::AudioEnd => SamplingClass::Structural,
+                _ => SamplingClass::Text,
+            },
+            GrammarState::Codes { .. } if top == TokenClass::CodeEnd => SamplingClass::Structural,
+            GrammarState::Codes { .. } => SamplingClass::AudioCode,
+            GrammarState::Time | GrammarState::WordEnd | GrammarState::Done => SamplingClass::Structural,
+        }
+    }
+
+    fn accept(&mut self, token: LlamaToken) {
+        self.state = self.state.advance(self.classes.get(token));
+    }
+}
+
+/// Stops on `<|audio_end|>`, or once as many word blocks (`<|code_end|>`)
+/// have been generated as there are input words. Empty words, which a
+/// plain split on spaces produces for repeated spaces, are not counted.
+pub struct WordCountStop {
+    code_end: i32,
+    audio_end: i32,
+    words: usize,
+    spoken: usize,
+}
+
+impl WordCountStop {
+    pub fn new(processor: &PromptProcessor, words: &[String]) -> anyhow::Result<Self> {
+        Ok(Self::from_ids(
+            processor.special_token_id("code_end")? as i32,
+            processor.special_token_id("audio_end")? as i32,
+            words,
+        ))
+    }
+
+    /// For layouts whose word blocks end in another token, e.g. 1.0's `<|word_end|>`.
+    pub fn from_ids(word_end: i32, audio_end: i32, words: &[String]) -> Self {
+        WordCountStop {
+            code_end: word_end,
+            audio_end,
+            words: words.iter().filter(|word| !word.trim().is_empty()).count(),
+            spoken: 0,
+        }
+    }
+}
+
+impl StopRule for WordCountStop {
+    fn check(&mut self, token: LlamaToken) -> Option<StopReason> {
+        if token.0 == self.audio_end {
+            return Some(StopReason::AudioEnd);
+        }
+        if token.0 == self.code_end {
+            self.spoken += 1;
+            if self.spoken >= self.words {
+                return Some(StopReason::AllWordsSpoken);
+            }
+        }
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::prompt_format::PromptVersion;
+
+    fn token(processor: &PromptProcessor, piece: &str) -> LlamaToken {
+        let ids = processor.encode_prompt(piece).unwrap();
+        assert_eq!(ids.len(), 1, "{} is not a single token", piece);
+        LlamaToken(ids[0] as i32)
+    }
+
+    #[test]
+    fn test_layout_sequence() {
+        let processor = PromptProcessor::new().unwrap();
+        let classes = Arc::new(TokenClasses::new(&processor).unwrap());
+        let mut grammar = AudioGrammar::new(classes, GrammarState::Word { tokens: 0 }, None);
+
+        let sequence = ["hello", "<|t_0.30|>", "<|code_start|>", "<|12|>", "<|34|>", "<|code_end|>", "\n", "<|audio_end|>"];
+        for piece in sequence {
+            let t = token(&processor, piece);
+            assert!(grammar.allows(t), "{} rejected in {:?}", piece, grammar.state());
+            grammar.accept(t);
+        }
+        assert_eq!(grammar.state(), GrammarState::Done);
+    }
+
+    #[test]
+    fn test_layout_rejects_malformed() {
+        let processor = PromptProcessor::new().unwrap();
+        let classes = Arc::new(TokenClasses::new(&processor).unwrap());
+        let mut grammar = AudioGrammar::new(classes, GrammarState::Word { tokens: 0 }, None);
+
+        // A time token needs a word first, and codes need <|code_start|>
+        assert!(!grammar.allows(token(&processor, "<|t_0.30|>")));
+        assert!(!grammar.allows(token(&processor, "<|12|>")));
+        grammar.accept(token(&processor, "hello"));
+        assert!(!grammar.allows(token(&processor, "<|code_start|>")));
+        grammar.accept(token(&processor, "<|t_0.30|>"));
+        assert!(!grammar.allows(token(&processor, "<|12|>")));
+        grammar.accept(token(&processor, "<|code_start|>"));
+        assert!(!grammar.allows(token(&processor, "<|code_end|>")));
+    }
+
+    #[test]
+    fn test_forced_words() {
+        let processor = PromptProcessor::new().unwrap();
+        let classes = Arc::new(TokenClasses::new(&processor).unwrap());
+        let hello: Vec<i32> = processor.encode_prompt("hello").unwrap().iter().map(|&x| x as i32).collect();
+        let mut grammar = AudioGrammar::new(classes, GrammarState::Word { tokens: 0 }, Some(vec![hello]));
+
+        assert!(!grammar.allows(token(&processor, "world")));
+        assert!(grammar.allows(token(&processor, "hello")));
+        for piece in ["hello", "<|t_0.30|>", "<|code_start|>", "<|12|>", "<|code_end|>", "\n"] {
+            grammar.accept(token(&processor, piece));
+        }
+        // All words spoken: only the end of audio remains
+        assert!(!grammar.allows(token(&processor, "hello")));
+        assert!(grammar.allows(token(&processor, "<|audio_end|>")));
+    }
+
+    #[test]
+    fn test_layout_classifier() {
+        let processor = PromptProcessor::new().unwrap();
+        let classes = Arc::new(TokenClasses::new(&processor).unwrap());
+        let mut classifier = LayoutClassifier::new(classes, GrammarState::Word { tokens: 0 });
+
+        let sequence = [
+            ("hello", SamplingClass::Text),
+            ("<|t_0.30|>", SamplingClass::Time),
+            ("<|code_start|>", SamplingClass::Structural),
+            ("<|12|>", SamplingClass::AudioCode),
+            ("<|code_end|>", SamplingClass::Structural),
+            ("\n", SamplingClass::Structural),
+            ("<|audio_end|>", SamplingClass::Structural),
+        ];
+        for (piece, class) in sequence {
+            let t = token(&processor, piece);
+            assert_eq!(classifier.next_class(t), class, "{}", piece);
+            assert!(classifier.fixed_class().is_none_or(|fixed| fixed == class), "{}", piece);
+            classifier.accept(t);
+        }
+    }
+
+    #[test]
+    fn test_word_count_stop() {
+        let processor = PromptProcessor::new().unwrap();
+        let code_end = token(&processor, "<|code_end|>");
+        let words = ["hello".to_string(), "world".to_string()];
+        let mut stop = WordCountStop::new(&processor, &words).unwrap();
+
+        assert_eq!(stop.check(token(&processor, "hello")), None);
+        assert_eq!(stop.check(code_end), None);
+        assert_eq!(stop.check(code_end), Some(StopReason::AllWordsSpoken));
+
+        let mut stop = WordCountStop::new(&processor, &words).unwrap();
+        assert_eq!(stop.check(token(&processor, "<|audio_end|>")), Some(StopReason::AudioEnd));
+    }
+
+    #[test]
+    fn test_word_count_ignores_empty_words() {
+        let processor = PromptProcessor::new().unwrap();
+        let code_end = token(&processor, "<|code_end|>");
+        let words: Vec<String> = "hello  world ".split(' ').map(str::to_string).collect();
+        let mut stop = WordCountStop::new(&processor, &words).unwrap();
+
+        assert_eq!(stop.check(code_end), None);
+        assert_eq!(stop.check(code_end), Some(StopReason::AllWordsSpoken));
+
+        // Every prompt format must agree with the count the stop rule uses
+        for version in [PromptVersion::V0_1, PromptVersion::V0_2, PromptVersion::V0_3] {
+            let words = version.format().unwrap().sanitize_words("hello   there,  world");
+            assert_eq!(words, words.iter().filter(|w| !w.is_empty()).cloned().collect::<Vec<_>>());
+            assert_eq!(words.len(), 3, "{:?}", version);
+        }
+    }
+}
@@ -1,28 +1,213 @@
 use anyhow::Result;
-use crate::model::{GGUFModel, GenerationConfig};
+use crate::backend::{LanguageModel, TokenSource};
+use crate::model::{
+    AnyStop, BatchSequence, GGUFModel, GenerationConfig, LoraAdapter, ModelTuning, SamplingClassifier, StopReason,
+    StopRule, TokenFilter,
+};
+use crate::grammar::{AudioGrammar, ConstraintMode, GrammarState, LayoutClassifier, TokenClasses, WordCountStop};
+use crate::loop_detect::{LoopDetector, LoopPolicy};
+use crate::prompt_format::PromptVersion;
+use crate::dac_codec::DacCodec;
+use crate::dac_prompt::{DacPromptProcessor, DacSpeaker};
 use crate::prompt_processor::PromptProcessor;
-use crate::audio_codec::AudioCodec;
+use crate::scoring::{self, CandidateScore};
+use crate::trace::{self, TokenTrace, WordConfidence};
+use crate::audio_codec::{AudioCodec, StreamingDecoder};
 use crate::default_speakers::DEFAULT_SPEAKERS;
 use ndarray::Array;
 use ndarray::IxDyn;
+use serde::Serialize;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 use crate::types::Speaker;
 
 pub struct GGUFModelConfig {
     pub model_path: String,
     pub language: String,
     pub verbose: bool,
-    pub max_seq_length: usize,
+    /// Context size; 4096 tokens (or the training context, if shorter) when
+    /// `None`. Never exceeds the model's training context length
+    pub max_seq_length: Option<usize>,
     pub n_gpu_layers: u32,
+    /// Evaluate each speaker's reference prompt once and reuse its KV state.
+    /// This changes the prompt layout: the reference becomes a closed turn
+    /// of its own ([`PromptProcessor::get_speaker_prompt`]) ahead of a
+    /// speaker-less prompt for the text, instead of sharing the text's
+    /// turn. OuteTTS 0.x was not trained on that layout, so output can
+    /// differ from the uncached prompt.
+    pub cache_speaker_prompt: bool,
+    /// Where to persist cached speaker states, if anywhere
+    pub speaker_cache_dir: Option<String>,
+    /// Number of contexts sharing the model weights, i.e. concurrent requests
+    pub n_contexts: usize,
+    /// How long a request waits for a free context; `None` waits forever
+    pub context_timeout: Option<Duration>,
+    pub tuning: ModelTuning,
+    /// Prompt layout; detected from the model name when `None`
+    pub prompt_version: Option<PromptVersion>,
+    /// Encode prompts with the GGUF's embedded vocabulary instead of
+    /// `models/tokenizer.json`
+    pub use_model_tokenizer: bool,
+    /// LoRA adapters applied on top of the base model unless a request
+    /// picks its own in [`GenerationConfig::lora`]
+    pub lora_adapters: Vec<LoraAdapter>,
 }
 
+/// Per-request timings and counts. Times are in seconds.
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct GenerationMetrics {
+    pub prompt_tokens: usize,
+    pub generated_tokens: usize,
+    pub audio_codes: usize,
+    /// With `best_of`, this and `generation_time` are the returned
+    /// candidate's, like the counts
+    pub prompt_eval_time: f64,
+    /// Time spent sampling, not counting prompt evaluation
+    pub generation_time: f64,
+    /// Generated tokens per second of `generation_time`
+    pub tokens_per_second: f64,
+    /// Time spent in `AudioCodec::decode`
+    pub decode_time: f64,
+    pub audio_duration: f64,
+    /// Total processing time divided by `audio_duration`; below 1 is faster than real time
+    pub real_time_factor: f64,
+    /// Times generation resumed after a detected loop
+    pub loop_retries: usize,
+}
+
+impl GenerationMetrics {
+    /// Fills in the derived rates from the raw counts and times.
+    fn finish(mut self) -> Self {
+        if self.generation_time > 0.0 {
+            self.tokens_per_second = self.generated_tokens as f64 / self.generation_time;
+        }
+        if self.audio_duration > 0.0 {
+            let total = self.prompt_eval_time + self.generation_time + self.decode_time;
+            self.real_time_factor = total / self.audio_duration;
+        }
+        self
+    }
+}
+
+/// Why [`InterfaceGGUF::generate`] stopped before finishing. Returned inside
+/// `anyhow::Error`; downcast to get the audio generated up to that point.
+#[derive(Debug)]
+pub enum GenerationError {
+    /// The request's [`crate::model::CancellationToken`] was cancelled.
+    Cancelled { partial: ModelOutput },
+    /// The request's deadline passed.
+    TimedOut { partial: ModelOutput },
+}
+
+impl GenerationError {
+    fn new(reason: StopReason, partial: ModelOutput) -> Self {
+        match reason {
+            StopReason::TimedOut => GenerationError::TimedOut { partial },
+            _ => GenerationError::Cancelled { partial },
+        }
+    }
+
+    /// Audio produced before the interruption.
+    pub fn partial(&self) -> &ModelOutput {
+        match self {
+            GenerationError::Cancelled { partial } | GenerationError::TimedOut { partial } => partial,
+        }
+    }
+}
+
+impl std::fmt::Display for GenerationError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let seconds = self.partial().metrics().audio_duration;
+        match self {
+            GenerationError::Cancelled { .. } => write!(f, "Generation cancelled after {:.2}s of audio", seconds),
+            GenerationError::TimedOut { .. } => write!(f, "Generation timed out after {:.2}s of audio", seconds),
+        }
+    }
+}
+
+impl std::error::Error for GenerationError {}
+
+#[derive(Debug)]
 pub struct ModelOutput {
     audio: Vec<f32>,
     sr: u32,
+    seed: u32,
+    stop_reason: Option<StopReason>,
+    metrics: GenerationMetrics,
+    candidates: Vec<CandidateScore>,
+    trace: Vec<TokenTrace>,
+    words: Vec<WordConfidence>,
 }
 
 impl ModelOutput {
-    pub fn new(audio: Vec<f32>, sr: u32) -> Self {
-        ModelOutput { audio, sr }
+    pub fn new(
+        audio: Vec<f32>,
+        sr: u32,
+        seed: u32,
+        stop_reason: Option<StopReason>,
+        metrics: GenerationMetrics,
+    ) -> Self {
+        ModelOutput {
+            audio,
+            sr,
+            seed,
+            stop_reason,
+            metrics,
+            candidates: Vec::new(),
+            trace: Vec::new(),
+            words: Vec::new(),
+        }
+    }
+
+    /// Records the scores of every `best_of` candidate this output won against.
+    pub fn with_candidates(mut self, candidates: Vec<CandidateScore>) -> Self {
+        self.candidates = candidates;
+        self
+    }
+
+    /// Scores of all sampled candidates, in seed order, the winner included.
+    pub fn candidates(&self) -> &[CandidateScore] {
+        &self.candidates
+    }
+
+    /// Attaches the per-token trace and the word blocks rated from it.
+    pub fn with_trace(mut self, trace: Vec<TokenTrace>, words: Vec<WordConfidence>) -> Self {
+        self.trace = trace;
+        self.words = words;
+        self
+    }
+
+    /// Every generated token, when `GenerationConfig::trace_top_k` was set.
+    pub fn trace(&self) -> &[TokenTrace] {
+        &self.trace
+    }
+
+    /// Confidence of each generated word block, when traced.
+    pub fn words(&self) -> &[WordConfidence] {
+        &self.words
+    }
+
+    /// Mean confidence of the word blocks, when traced.
+    pub fn confidence(&self) -> Option<f32> {
+        let scores: Vec<f32> = self.words.iter().filter_map(|w| w.confidence).collect();
+        if scores.is_empty() {
+            return None;
+        }
+        Some(scores.iter().sum::<f32>() / scores.len() as f32)
+    }
+
+    pub fn metrics(&self) -> &GenerationMetrics {
+        &self.metrics
+    }
+
+    /// Why generation ended; `None` if it failed midway.
+    pub fn stop_reason(&self) -> Option<StopReason> {
+        self.stop_reason
+    }
+
+    /// Sampling seed the audio was generated with.
+    pub fn seed(&self) -> u32 {
+        self.seed
     }
 
     pub fn save(&self, path: &str) -> Result<()> {
@@ -51,66 +236,196 @@ impl ModelOutput {
     }
 }
 
+/// One sampled continuation, before scoring.
+struct Candidate {
+    tokens: Vec<i64>,
+    /// Per-token log-probabilities, when the backend reports them
+    logprobs: Vec<f32>,
+    /// One entry per token when the request asked for a trace
+    trace: Vec<TokenTrace>,
+    stop_reason: Option<StopReason>,
+    prompt_eval_time: Duration,
+    /// Time spent sampling, after each attempt's prompt evaluation
+    generation_time: Duration,
+    loop_retries: usize,
+}
+
 pub struct InterfaceGGUF {
     config: GGUFModelConfig,
+    model: Box<dyn LanguageModel>,
+    max_seq_length: usize,
+    pipeline: Pipeline,
+}
+
+/// What [`InterfaceGGUF::generation_rules`] attaches to a generation.
+struct GenerationRules {
+    stop_rule: Box<dyn StopRule>,
+    filter: Option<Box<dyn TokenFilter>>,
+    classifier: Option<Box<dyn SamplingClassifier>>,
+}
+
+/// Runs blocking work (context checkout, decoding) from async code without
+/// stalling the tokio worker it was called on: on a multi-threaded runtime
+/// the worker hands its other tasks over first. Elsewhere it simply runs.
+fn run_blocking<T>(work: impl FnOnce() -> T) -> T {
+    match tokio::runtime::Handle::try_current() {
+        Ok(handle) if handle.runtime_flavor() == tokio::runtime::RuntimeFlavor::MultiThread => {
+            tokio::task::block_in_place(work)
+        }
+        _ => work(),
+    }
+}
+
+/// Prompt building and audio decoding for the detected prompt version.
+/// Only the assets of that version are loaded.
+enum Pipeline {
+    /// OuteTTS 0.1 to 0.3: one WavTokenizer code per audio token
+    Legacy(LegacyPipeline),
+    /// OuteTTS 1.0: two interleaved DAC codebooks
+    Dac(DacPipeline),
+}
+
+struct LegacyPipeline {
     prompt_processor: PromptProcessor,
     audio_codec: AudioCodec,
-    model: GGUFModel,
+    token_classes: Arc<TokenClasses>,
+}
+
+struct DacPipeline {
+    prompt_processor: DacPromptProcessor,
+    codec: DacCodec,
 }
 
 impl InterfaceGGUF {
     pub async fn new(config: GGUFModelConfig) -> Result<Self> {
+        let model = run_blocking(|| GGUFModel::load(&config))?;
+        Self::with_backend(config, Box::new(model))
+    }
+
+    /// Builds the interface around an already loaded backend, e.g. a
+    /// [`crate::backend::ScriptedModel`] in tests.
+    pub fn with_backend(config: GGUFModelConfig, model: Box<dyn LanguageModel>) -> Result<Self> {
         if config.verbose {
             println!("Available speakers:");
             for (language, speakers) in DEFAULT_SPEAKERS.iter() {
                 println!("Language '{}' speakers:", language);
-                for (name, path) in speakers.iter() {
-                    println!("  - {}: {}", name, path.as_str().unwrap_or("Invalid path"));
+                for name in speakers.keys() {
+                    println!("  - {}", name);
                 }
             }
             println!();
         }
 
-        // Initialize prompt processor with tokenizer
-        let prompt_processor = PromptProcessor::new()?;
+        let metadata = model.metadata();
+        if let (Some(metadata), true) = (metadata, config.verbose) {
+            println!("Model: {}", metadata.name.as_deref().unwrap_or("(unnamed)"));
+            println!("Architecture: {}", metadata.architecture.as_deref().unwrap_or("(unknown)"));
+            println!("Vocabulary: {} tokens", metadata.n_vocab);
+            if let Some(context_length) = metadata.context_length {
+                println!("Training context: {} tokens", context_length);
+            }
+        }
+
+        let prompt_version = config.prompt_version
+            .or_else(|| metadata.and_then(|m| m.prompt_version()))
+            .unwrap_or(PromptVersion::V0_2);
+        if config.verbose {
+            println!("Prompt format: {:?}", prompt_version);
+        }
+
+        let model_tokenizer = if config.use_model_tokenizer {
+            Some(model.tokenizer()
+                .ok_or_else(|| anyhow::anyhow!("This backend has no embedded tokenizer"))?)
+        } else {
+            None
+        };
 
-        // Initialize model
-        let model = GGUFModel::new(
-            &config.model_path,
-            config.n_gpu_layers,
-            config.max_seq_length
-        )?;
+        let pipeline = if prompt_version.uses_dac() {
+            Pipeline::Dac(DacPipeline {
+                prompt_processor: match model_tokenizer {
+                    Some(tokenizer) => DacPromptProcessor::with_tokenizer(tokenizer)?,
+                    None => DacPromptProcessor::new()?,
+                },
+                codec: DacCodec::new()?,
+            })
+        } else {
+            let prompt_processor = match model_tokenizer {
+                Some(tokenizer) => PromptProcessor::with_tokenizer(prompt_version, tokenizer)?,
+                None => PromptProcessor::with_version(prompt_version)?,
+            };
+            let token_classes = Arc::new(TokenClasses::new(&prompt_processor)?);
+            Pipeline::Legacy(LegacyPipeline {
+                prompt_processor,
+                audio_codec: AudioCodec::new()?,
+                token_classes,
+            })
+        };
 
-        // Initialize audio codec
-        let audio_codec = AudioCodec::new()?;
+        // A model without the format's tokens, or with other ids for them than
+        // the prompt tokenizer, would produce garbage. With the embedded
+        // tokenizer this still catches non-OuteTTS models, whose vocabulary
+        // lacks the special tokens.
+        if let Some(metadata) = metadata {
+            let expected = match &pipeline {
+                Pipeline::Dac(dac) => dac.prompt_processor.vocab_tokens()?,
+                Pipeline::Legacy(legacy) => legacy.prompt_processor.vocab_tokens()?,
+            };
+            metadata.check_vocab(&expected, |id| model.token_piece(id))
+                .map_err(|e| anyhow::anyhow!("{} is not a usable {:?} model: {}", config.model_path, prompt_version, e))?;
+        }
+
+        let max_seq_length = model.context_size()
+            .or(config.max_seq_length)
+            .unwrap_or(4096);
 
         Ok(InterfaceGGUF {
             config,
-            prompt_processor,
-            audio_codec,
             model,
+            max_seq_length,
+            pipeline,
         })
     }
 
-    async fn get_audio(&self, tokens: &[i64]) -> Result<Array<f32, IxDyn>> {
-        let output = self.prompt_processor.extract_audio_from_tokens(tokens);
+    /// Longest sequence, prompt included, a request may generate.
+    pub fn max_seq_length(&self) -> usize {
+        self.max_seq_length
+    }
+
+    /// The 0.1 to 0.3 pipeline, or an error naming `feature` as not yet
+    /// available for OuteTTS 1.0 models.
+    fn legacy(&self, feature: &str) -> Result<&LegacyPipeline> {
+        match &self.pipeline {
+            Pipeline::Legacy(legacy) => Ok(legacy),
+            Pipeline::Dac(_) => Err(anyhow::anyhow!("{} is not supported for OuteTTS 1.0 models yet", feature)),
+        }
+    }
+
+    fn get_audio(&self, legacy: &LegacyPipeline, tokens: &[i64]) -> Result<Array<f32, IxDyn>> {
+        let output = legacy.prompt_processor.extract_audio_from_tokens(tokens);
         if output.is_empty() {
             eprintln!("No audio tokens found in the output");
             return Ok(Array::default(IxDyn(&[]))); // Return an empty array
         }
 
         let tensor = Array::from_shape_vec(IxDyn(&[1, output.len()]), output.to_vec())?;
-        let decoded_audio = self.audio_codec.decode(tensor.as_slice().ok_or_else(|| anyhow::anyhow!("Failed to convert tensor to slice"))?)?;
+        let decoded_audio = legacy.audio_codec.decode(tensor.as_slice().ok_or_else(|| anyhow::anyhow!("Failed to convert tensor to slice"))?)?;
         Ok(decoded_audio)
     }
 
-    fn load_speaker(&self, path: &str) -> Result<serde_json::Value> {
+    pub fn load_speaker(&self, path: &str) -> Result<serde_json::Value> {
         let file_content = std::fs::read_to_string(path)?;
         let speaker_data: serde_json::Value = serde_json::from_str(&file_content)?;
         Ok(speaker_data)
     }
 
+    /// Whether the built-in speakers, which use the 0.x speaker format, can
+    /// be used with this model.
+    pub fn supports_default_speakers(&self) -> bool {
+        matches!(self.pipeline, Pipeline::Legacy(_))
+    }
+
     pub fn load_default_speaker(&self, name: &str) -> Result<serde_json::Value> {
+        self.legacy("Loading a built-in speaker")?;
         let name = name.to_lowercase().trim().to_string();
         let language = self.config.language.to_lowercase().trim().to_string();
         
@@ -135,73 +450,547 @@ impl InterfaceGGUF {
             ));
         }
 
-        let speaker_path = speakers[&name].as_str().ok_or_else(|| 
-            anyhow::anyhow!("Invalid speaker path format for {}", name)
-        )?;
-
-        if self.config.verbose {
-            println!("Loading speaker file from: {}", speaker_path);
-        }
-
-        self.load_speaker(speaker_path)
+        // Default speakers are embedded at compile time, see default_speakers.rs
+        Ok(speakers[&name].clone())
     }
 
-    fn check_generation_max_length(&self, max_length: Option<usize>) -> Result<()> {
-        if max_length.is_none() {
-            return Err(anyhow::anyhow!("max_length must be specified."));
-        }
-        if max_length.unwrap() > self.config.max_seq_length {
+    fn check_generation_max_length(&self, max_length: usize) -> Result<()> {
+        if max_length > self.max_seq_length {
             return Err(anyhow::anyhow!(
                 "Requested max_length ({}) exceeds the current max_seq_length ({})",
-                max_length.unwrap(),
-                self.config.max_seq_length
+                max_length,
+                self.max_seq_length
             ));
         }
         Ok(())
     }
 
-    fn prepare_prompt(&self, text: &str, speaker: Option<&serde_json::Value>) -> Result<Vec<i64>> {
+    fn prepare_prompt(&self, legacy: &LegacyPipeline, text: &str, speaker: Option<&serde_json::Value>) -> Result<Vec<i64>> {
         let speaker = if let Some(s) = speaker {
             Some(serde_json::from_value::<Speaker>(s.clone())
                 .map_err(|e| anyhow::Error::msg(e.to_string()))?)
         } else {
             None
         };
-        let prompt = self.prompt_processor.get_completion_prompt(text, &self.config.language, speaker.as_ref());
-        let encoded = self.prompt_processor.encode_prompt(prompt.as_str())?;
+
+        if let (true, Some(speaker)) = (self.config.cache_speaker_prompt, speaker.as_ref()) {
+            let speaker_prompt = legacy.prompt_processor.get_speaker_prompt(speaker);
+            let speaker_ids = legacy.prompt_processor.encode_prompt(&speaker_prompt)?;
+            let speaker_ids_i32: Vec<i32> = speaker_ids.iter().map(|&x| x as i32).collect();
+            self.model.cache_prefix(&speaker_ids_i32)?;
+
+            let prompt = legacy.prompt_processor.get_completion_prompt(text, &self.config.language, None);
+            return legacy.prompt_processor.encode_prompt(&(speaker_prompt + &prompt));
+        }
+
+        let prompt = legacy.prompt_processor.get_completion_prompt(text, &self.config.language, speaker.as_ref());
+        let encoded = legacy.prompt_processor.encode_prompt(prompt.as_str())?;
         Ok(encoded)
     }
 
+    /// Starts the model on `input_ids` with the word-count and loop stop
+    /// rules, the audio layout grammar when `generation_config.constrain`
+    /// asks for it, and the layout classifier when it sets per-class
+    /// sampling. `spoken` words of `text` are already in `input_ids`.
+    fn start_stream(
+        &self,
+        legacy: &LegacyPipeline,
+        text: &str,
+        input_ids: &[i64],
+        generation_config: &GenerationConfig,
+        spoken: usize,
+    ) -> Result<Box<dyn TokenSource<'_> + '_>> {
+        let rules = self.generation_rules(legacy, text, input_ids, generation_config, spoken)?;
+        let input_ids_i32: Vec<i32> = input_ids.iter().map(|&x| x as i32).collect();
+        let mut tokens = self.model.generate_stream(&input_ids_i32, generation_config)?;
+        tokens.set_stop_rule(rules.stop_rule);
+        if let Some(filter) = rules.filter {
+            tokens.set_filter(filter);
+        }
+        if let Some(classifier) = rules.classifier {
+            tokens.set_classifier(classifier);
+        }
+        Ok(tokens)
+    }
+
+    /// The stop rule, grammar and classifier that
+    /// [`InterfaceGGUF::start_stream`] applies to a generation.
+    fn generation_rules(
+        &self,
+        legacy: &LegacyPipeline,
+        text: &str,
+        input_ids: &[i64],
+        generation_config: &GenerationConfig,
+        spoken: usize,
+    ) -> Result<GenerationRules> {
+        let mut words = legacy.prompt_processor.process_text(text, &self.config.language);
+        words.drain(..spoken.min(words.len()));
+
+        let mut stop_rules: Vec<Box<dyn StopRule>> = vec![
+            Box::new(WordCountStop::new(&legacy.prompt_processor, &words)?),
+        ];
+        let loop_detection = &generation_config.loop_detection;
+        if loop_detection.policy != LoopPolicy::Off {
+            stop_rules.push(Box::new(LoopDetector::new(legacy.token_classes.clone(), loop_detection, &words)));
+        }
+        let stop_rule: Box<dyn StopRule> = Box::new(AnyStop(stop_rules));
+
+        // With a speaker in the interleaved layout the prompt ends on its last word block
+        let code_end = legacy.prompt_processor.special_token_id("code_end")?;
+        let initial = if input_ids.last() == Some(&code_end) {
+            GrammarState::WordEnd
+        } else {
+            GrammarState::Word { tokens: 0 }
+        };
+        let classifier: Option<Box<dyn SamplingClassifier>> = if generation_config.class_sampling.is_empty() {
+            None
+        } else {
+            Some(Box::new(LayoutClassifier::new(legacy.token_classes.clone(), initial)))
+        };
+        if generation_config.constrain == ConstraintMode::Off {
+            return Ok(GenerationRules { stop_rule, filter: None, classifier });
+        }
+
+        let words = if generation_config.constrain == ConstraintMode::Words {
+            Some(words.iter()
+                .map(|w| {
+                    let word = legacy.prompt_processor.format().audio_word(w.trim());
+                    Ok(legacy.prompt_processor.encode_prompt(&word)?.iter().map(|&x| x as i32).collect())
+                })
+                .collect::<Result<Vec<Vec<i32>>>>()?)
+        } else {
+            None
+        };
+
+        let grammar: Box<dyn TokenFilter> = Box::new(AudioGrammar::new(legacy.token_classes.clone(), initial, words));
+        Ok(GenerationRules { stop_rule, filter: Some(grammar), classifier })
+    }
+
+    /// Speaks `text`, in the voice of `speaker` if given. Fails with a
+    /// [`GenerationError`] carrying the audio so far when
+    /// `generation_config.cancel` is cancelled or its deadline passes.
     pub async fn generate(
         &self,
         text: &str,
         speaker: Option<&serde_json::Value>,
-        temperature: Option<f32>,
-        repetition_penalty: Option<f32>,
-        max_length: Option<usize>,
+        generation_config: &GenerationConfig,
+    ) -> Result<ModelOutput> {
+        run_blocking(|| self.generate_blocking(text, speaker, generation_config))
+    }
+
+    fn generate_blocking(
+        &self,
+        text: &str,
+        speaker: Option<&serde_json::Value>,
+        generation_config: &GenerationConfig,
     ) -> Result<ModelOutput> {
-        let input_ids = self.prepare_prompt(text, speaker)?;
+        let legacy = match &self.pipeline {
+            Pipeline::Legacy(legacy) => legacy,
+            Pipeline::Dac(dac) => return self.generate_dac(dac, text, speaker, generation_config),
+        };
+
+        let input_ids = self.prepare_prompt(legacy, text, speaker)?;
         if self.config.verbose {
             println!("Input tokens: {}", input_ids.len());
             println!("Generating audio...");
         }
 
-        self.check_generation_max_length(max_length)?;
+        self.check_generation_max_length(generation_config.max_length)?;
+        generation_config.validate()?;
+        let generation_config = generation_config.with_resolved_seed();
+        let first_seed = generation_config.seed.unwrap_or_default();
+        if self.config.verbose {
+            println!("Seed: {}", first_seed);
+        }
 
-        let input_ids_i32: Vec<i32> = input_ids.iter().map(|&x| x as i32).collect();
-        let output_i32 = self.model.generate(&input_ids_i32, &GenerationConfig {
-            temperature: temperature.unwrap_or(0.1),
-            max_length: max_length.unwrap_or(4096),
-            repetition_penalty: repetition_penalty.unwrap_or(1.1),
-        })?;
-        let output: Vec<i64> = output_i32.iter().map(|&x| x as i64).collect();
-
-        let audio = self.get_audio(&output).await?;
+        let words = legacy.prompt_processor.process_text(text, &self.config.language);
+        let mut best: Option<(Candidate, CandidateScore)> = None;
+        let mut scores = Vec::new();
+        let mut loop_retries = 0;
+        for i in 0..generation_config.best_of.max(1) {
+            let seed = generation_config.candidate_seed(i as u32).unwrap_or(first_seed);
+            let config = GenerationConfig { seed: Some(seed), ..generation_config.clone() };
+            let candidate = self.sample_candidate(legacy, text, &input_ids, &config)?;
+            loop_retries += candidate.loop_retries;
+
+            let score = scoring::score(&legacy.token_classes, &words, &candidate.tokens, &candidate.logprobs, seed);
+            if self.config.verbose && generation_config.best_of > 1 {
+                println!("Candidate {} (seed {}): score {:.3}", i + 1, seed, score.total);
+            }
+            let interrupted = candidate.stop_reason.is_some_and(StopReason::is_interruption);
+            if best.as_ref().is_none_or(|(_, best)| score.total > best.total) {
+                best = Some((candidate, score.clone()));
+            }
+            scores.push(score);
+            if interrupted {
+                break;
+            }
+        }
+        // Timings are the returned candidate's, so they match its token count
+        let Some((Candidate { tokens: output, stop_reason, trace, prompt_eval_time, generation_time, .. }, score)) = best else {
+            unreachable!("best_of is at least 1");
+        };
+        // An interrupted winner still decodes what it has, as partial audio.
+        // A winner that finished is not an error, even if the deadline
+        // passes while decoding or a later candidate was cut short.
+        let interrupted = stop_reason.filter(|reason| reason.is_interruption());
+
+        let decode_started = Instant::now();
+        let audio = self.get_audio(legacy, &output)?.into_raw_vec();
+        let decode_time = decode_started.elapsed();
         if self.config.verbose {
             println!("Audio generation completed");
         }
 
-        Ok(ModelOutput::new(audio.into_raw_vec(), self.audio_codec.get_sr()))
+        let sr = legacy.audio_codec.get_sr();
+        let metrics = GenerationMetrics {
+            prompt_tokens: input_ids.len(),
+            generated_tokens: output.len(),
+            audio_codes: legacy.prompt_processor.extract_audio_from_tokens(&output).len(),
+            prompt_eval_time: prompt_eval_time.as_secs_f64(),
+            generation_time: generation_time.as_secs_f64(),
+            decode_time: decode_time.as_secs_f64(),
+            audio_duration: audio.len() as f64 / sr as f64,
+            loop_retries,
+            ..Default::default()
+        }.finish();
+
+        let words = trace::word_confidence(&legacy.token_classes, &trace);
+        let output = ModelOutput::new(audio, sr, score.seed, stop_reason, metrics)
+            .with_candidates(scores)
+            .with_trace(trace, words);
+        match interrupted {
+            Some(reason) => Err(GenerationError::new(reason, output).into()),
+            None => Ok(output),
+        }
+    }
+
+    /// Generates one candidate for [`InterfaceGGUF::generate`], applying the
+    /// loop policy. Only the generated part is returned: the prompt carries
+    /// the speaker's reference codes.
+    fn sample_candidate(
+        &self,
+        legacy: &LegacyPipeline,
+        text: &str,
+        input_ids: &[i64],
+        generation_config: &GenerationConfig,
+    ) -> Result<Candidate> {
+        let code_end = legacy.prompt_processor.special_token_id("code_end")?;
+        let loop_detection = &generation_config.loop_detection;
+        let mut attempt_config = generation_config.clone();
+        let mut output = Vec::new();
+        let mut logprobs = Vec::new();
+        let mut trace = Vec::new();
+        let mut loop_retries = 0;
+        let mut prompt_eval_time = Duration::ZERO;
+        let mut generation_time = Duration::ZERO;
+        let stop_reason = loop {
+            let spoken = output.iter().filter(|&&t| t == code_end).count();
+            let prompt = [input_ids, output.as_slice()].concat();
+            let mut tokens = match self.start_stream(legacy, text, &prompt, &attempt_config, spoken) {
+                Ok(tokens) => tokens,
+                // Cut short while waiting for a context: keep what earlier attempts made
+                Err(_) if attempt_config.interruption().is_some() => break attempt_config.interruption(),
+                Err(e) => return Err(e),
+            };
+            let mut segment = Vec::new();
+            let mut segment_logprobs = Vec::new();
+            let mut segment_trace = Vec::new();
+            let segment_started = Instant::now();
+            while let Some(token) = tokens.next() {
+                let token = token?.0;
+                segment.push(token as i64);
+                segment_logprobs.extend(tokens.last_logprob());
+                if generation_config.trace_top_k.is_some() {
+                    segment_trace.push(TokenTrace::new(
+                        legacy.prompt_processor.tokenizer.as_ref(),
+                        token,
+                        tokens.last_logprob(),
+                        tokens.last_alternatives(),
+                    ));
+                }
+            }
+            generation_time += segment_started.elapsed();
+            let stop_reason = tokens.stop_reason();
+            prompt_eval_time += tokens.prompt_eval_time();
+            drop(tokens);
+            if self.config.verbose {
+                println!("Stopped: {:?}", stop_reason);
+            }
+            if stop_reason != Some(StopReason::Loop) {
+                output.extend(segment);
+                logprobs.extend(segment_logprobs);
+                trace.extend(segment_trace);
+                break stop_reason;
+            }
+
+            // Everything up to the last finished word block is still good
+            let good = segment.iter().rposition(|&t| t == code_end).map_or(0, |i| i + 1);
+            output.extend_from_slice(&segment[..good]);
+            logprobs.extend(segment_logprobs.into_iter().take(good));
+            trace.extend(segment_trace.into_iter().take(good));
+            match loop_detection.policy {
+                LoopPolicy::Abort => {
+                    return Err(anyhow::anyhow!(
+                        "Generation got stuck in a loop after {} words",
+                        output.iter().filter(|&&t| t == code_end).count()
+                    ));
+                }
+                LoopPolicy::Retry if loop_retries < loop_detection.max_retries => {
+                    loop_retries += 1;
+                    attempt_config.seed = generation_config.retry_seed(loop_retries as u32);
+                    attempt_config.repetition_penalty += loop_detection.retry_penalty_step;
+                    if self.config.verbose {
+                        println!(
+                            "Loop detected, retrying with seed {:?} and repetition penalty {:.2}",
+                            attempt_config.seed, attempt_config.repetition_penalty
+                        );
+                    }
+                }
+                _ => break stop_reason,
+            }
+        };
+
+        Ok(Candidate {
+            tokens: output,
+            logprobs,
+            trace,
+            stop_reason,
+            prompt_eval_time,
+            generation_time,
+            loop_retries,
+        })
+    }
+
+    /// [`InterfaceGGUF::generate`] for OuteTTS 1.0: DAC prompt layout,
+    /// two interleaved codebooks and the DAC decoder.
+    fn generate_dac(
+        &self,
+        dac: &DacPipeline,
+        text: &str,
+        speaker: Option<&serde_json::Value>,
+        generation_config: &GenerationConfig,
+    ) -> Result<ModelOutput> {
+        if generation_config.constrain != ConstraintMode::Off {
+            return Err(anyhow::anyhow!("Constrained decoding is not supported for OuteTTS 1.0 models yet"));
+        }
+        if !generation_config.class_sampling.is_empty() {
+            return Err(anyhow::anyhow!("Per-class sampling is not supported for OuteTTS 1.0 models yet"));
+        }
+        if generation_config.trace_top_k.is_some() {
+            return Err(anyhow::anyhow!("Tracing is not supported for OuteTTS 1.0 models yet"));
+        }
+        self.check_generation_max_length(generation_config.max_length)?;
+        generation_config.validate()?;
+        let generation_config = generation_config.with_resolved_seed();
+        let seed = generation_config.seed.unwrap_or_default();
+
+        let speaker = speaker
+            .map(|s| serde_json::from_value::<DacSpeaker>(s.clone()))
+            .transpose()
+            .map_err(|e| anyhow::anyhow!("Invalid OuteTTS 1.0 speaker: {}", e))?;
+        let prompt = dac.prompt_processor.get_completion_prompt(text, speaker.as_ref());
+        let input_ids = dac.prompt_processor.encode_prompt(&prompt)?;
+        if self.config.verbose {
+            println!("Input tokens: {}", input_ids.len());
+        }
+
+        let word_end = dac.prompt_processor.single_token("<|word_end|>")? as i32;
+        let audio_end = dac.prompt_processor.single_token("<|audio_end|>")? as i32;
+        let words = dac.prompt_processor.process_text(text);
+
+        let input_ids_i32: Vec<i32> = input_ids.iter().map(|&x| x as i32).collect();
+        let mut tokens = self.model.generate_stream(&input_ids_i32, &generation_config)?;
+        tokens.set_stop_rule(Box::new(WordCountStop::from_ids(word_end, audio_end, &words)));
+
+        let generation_started = Instant::now();
+        let output = tokens.by_ref()
+            .map(|token| Ok(token?.0 as i64))
+            .collect::<Result<Vec<i64>>>()?;
+        let generation_time = generation_started.elapsed();
+        let stop_reason = tokens.stop_reason();
+        let prompt_eval_time = tokens.prompt_eval_time();
+        drop(tokens);
+
+        let (c1, c2) = dac.prompt_processor.extract_codes(&output);
+        let decode_started = Instant::now();
+        let audio = if c1.is_empty() {
+            eprintln!("No audio tokens found in the output");
+            Vec::new()
+        } else {
+            dac.codec.decode(&c1, &c2)?.into_raw_vec()
+        };
+        let decode_time = decode_started.elapsed();
+
+        let sr = dac.codec.get_sr();
+        let metrics = GenerationMetrics {
+            prompt_tokens: input_ids.len(),
+            generated_tokens: output.len(),
+            audio_codes: c1.len() + c2.len(),
+            prompt_eval_time: prompt_eval_time.as_secs_f64(),
+            generation_time: generation_time.as_secs_f64(),
+            decode_time: decode_time.as_secs_f64(),
+            audio_duration: audio.len() as f64 / sr as f64,
+            ..Default::default()
+        }.finish();
+
+        let output = ModelOutput::new(audio, sr, seed, stop_reason, metrics);
+        match stop_reason.filter(|reason| reason.is_interruption()) {
+            Some(reason) => Err(GenerationError::new(reason, output).into()),
+            None => Ok(output),
+        }
+    }
+
+    /// Speaks each of `texts` as its own sequence, decoding them together
+    /// when the backend supports it (see [`GGUFModel::generate_batch`]).
+    /// All sequences share `generation_config` and its seed. A detected loop
+    /// keeps the audio up to the last complete word; under
+    /// [`LoopPolicy::Abort`] only that sequence's entry is an error, the
+    /// others are kept. Batches never retry. An interrupted batch is not an
+    /// error: each output's stop reason says whether it was cut short.
+    pub async fn generate_batch(
+        &self,
+        texts: &[&str],
+        speaker: Option<&serde_json::Value>,
+        generation_config: &GenerationConfig,
+    ) -> Result<Vec<Result<ModelOutput>>> {
+        run_blocking(|| self.generate_batch_blocking(texts, speaker, generation_config))
+    }
+
+    fn generate_batch_blocking(
+        &self,
+        texts: &[&str],
+        speaker: Option<&serde_json::Value>,
+        generation_config: &GenerationConfig,
+    ) -> Result<Vec<Result<ModelOutput>>> {
+        let legacy = self.legacy("Batched generation")?;
+        if generation_config.best_of > 1 {
+            return Err(anyhow::anyhow!("best_of is not supported for batched generation"));
+        }
+        if generation_config.trace_top_k.is_some() {
+            return Err(anyhow::anyhow!("Tracing is not supported for batched generation"));
+        }
+        self.check_generation_max_length(generation_config.max_length)?;
+        generation_config.validate()?;
+        let generation_config = generation_config.with_resolved_seed();
+        let seed = generation_config.seed.unwrap_or_default();
+
+        let mut prompts = Vec::with_capacity(texts.len());
+        let mut sequences = Vec::with_capacity(texts.len());
+        for text in texts {
+            let input_ids = self.prepare_prompt(legacy, text, speaker)?;
+            let rules = self.generation_rules(legacy, text, &input_ids, &generation_config, 0)?;
+            let mut sequence = BatchSequence::new(
+                input_ids.iter().map(|&x| x as i32).collect(),
+                generation_config.clone(),
+            ).with_stop_rule(rules.stop_rule);
+            if let Some(filter) = rules.filter {
+                sequence = sequence.with_filter(filter);
+            }
+            if let Some(classifier) = rules.classifier {
+                sequence = sequence.with_classifier(classifier);
+            }
+            prompts.push(input_ids);
+            sequences.push(sequence);
+        }
+        if self.config.verbose {
+            println!("Generating {} sequences (seed {})...", sequences.len(), seed);
+        }
+
+        let outputs = self.model.generate_batch(sequences)?;
+
+        let code_end = legacy.prompt_processor.special_token_id("code_end")?;
+        let sr = legacy.audio_codec.get_sr();
+        let mut results = Vec::with_capacity(outputs.len());
+        for (input_ids, output) in prompts.iter().zip(outputs) {
+            let mut tokens: Vec<i64> = output.tokens.iter().map(|&x| x as i64).collect();
+            if output.stop_reason == Some(StopReason::Loop) {
+                if generation_config.loop_detection.policy == LoopPolicy::Abort {
+                    results.push(Err(anyhow::anyhow!("Generation got stuck in a loop")));
+                    continue;
+                }
+                let good = tokens.iter().rposition(|&t| t == code_end).map_or(0, |i| i + 1);
+                tokens.truncate(good);
+            }
+
+            let decode_started = Instant::now();
+            let audio = self.get_audio(legacy, &tokens)?.into_raw_vec();
+            let decode_time = decode_started.elapsed();
+
+            let metrics = GenerationMetrics {
+                prompt_tokens: input_ids.len(),
+                generated_tokens: tokens.len(),
+                audio_codes: legacy.prompt_processor.extract_audio_from_tokens(&tokens).len(),
+                prompt_eval_time: output.prompt_eval_time.as_secs_f64(),
+                generation_time: output.generation_time.as_secs_f64(),
+                decode_time: decode_time.as_secs_f64(),
+                audio_duration: audio.len() as f64 / sr as f64,
+                ..Default::default()
+            }.finish();
+            results.push(Ok(ModelOutput::new(audio, sr, seed, output.stop_reason, metrics)));
+        }
+        Ok(results)
+    }
+
+    /// Streaming counterpart of [`InterfaceGGUF::generate`]. Audio is decoded
+    /// every `words_per_chunk` completed words (`<|code_end|>`) and yielded as
+    /// PCM at [`AudioStream::sample_rate`]; the chunks concatenate to the same
+    /// waveform a single decode would produce. A detected loop ends the
+    /// stream after the last complete word (an error under
+    /// [`LoopPolicy::Abort`]); streams never retry. A cancelled or timed out
+    /// stream ends with a [`GenerationError`] carrying the audio not yielded
+    /// yet.
+    pub fn generate_stream(
+        &self,
+        text: &str,
+        speaker: Option<&serde_json::Value>,
+        generation_config: &GenerationConfig,
+        words_per_chunk: usize,
+    ) -> Result<AudioStream<'_>> {
+        let legacy = self.legacy("Streaming")?;
+        if generation_config.trace_top_k.is_some() {
+            return Err(anyhow::anyhow!("Tracing is not supported for streaming generation"));
+        }
+        let input_ids = self.prepare_prompt(legacy, text, speaker)?;
+        self.check_generation_max_length(generation_config.max_length)?;
+        generation_config.validate()?;
+        let generation_config = generation_config.with_resolved_seed();
+        if self.config.verbose {
+            println!("Seed: {}", generation_config.seed.unwrap_or_default());
+        }
+
+        let sr = legacy.audio_codec.get_sr();
+        let tokens = match self.start_stream(legacy, text, &input_ids, &generation_config, 0) {
+            Ok(tokens) => tokens,
+            Err(e) => {
+                // Cut short while waiting for a context, before any audio
+                let Some(reason) = generation_config.interruption() else {
+                    return Err(e);
+                };
+                let metrics = GenerationMetrics { prompt_tokens: input_ids.len(), ..Default::default() };
+                let partial = ModelOutput::new(Vec::new(), sr, generation_config.seed.unwrap_or_default(), Some(reason), metrics);
+                return Err(GenerationError::new(reason, partial).into());
+            }
+        };
+
+        Ok(AudioStream {
+            tokens,
+            prompt_processor: &legacy.prompt_processor,
+            decoder: StreamingDecoder::new(&legacy.audio_codec),
+            sr,
+            code_end: legacy.prompt_processor.special_token_id("code_end")?,
+            word_codes: Vec::new(),
+            abort_on_loop: generation_config.loop_detection.policy == LoopPolicy::Abort,
+            seed: generation_config.seed.unwrap_or_default(),
+            words_per_chunk: words_per_chunk.max(1),
+            words_pending: 0,
+            finished: false,
+            started: Instant::now(),
+            prompt_tokens: input_ids.len(),
+            generated_tokens: 0,
+            audio_codes: 0,
+            samples: 0,
+        })
     }
 
     pub fn validate_speaker(language: &str, speaker: &str) -> Result<bool> {
@@ -219,4 +1008,375 @@ impl InterfaceGGUF {
 
         Ok(true)
     }
-}
\ No newline at end of file
+}
+
+/// PCM chunks produced by [`InterfaceGGUF::generate_stream`], in order.
+pub struct AudioStream<'a> {
+    tokens: Box<dyn TokenSource<'a> + 'a>,
+    prompt_processor: &'a PromptProcessor,
+    decoder: StreamingDecoder<'a>,
+    sr: u32,
+    code_end: i64,
+    /// Codes of the word block in progress, held back until it completes
+    word_codes: Vec<i64>,
+    abort_on_loop: bool,
+    seed: u32,
+    words_per_chunk: usize,
+    words_pending: usize,
+    finished: bool,
+    /// For the metrics of an interrupted stream's partial output
+    started: Instant,
+    prompt_tokens: usize,
+    generated_tokens: usize,
+    audio_codes: usize,
+    /// Samples yielded so far
+    samples: usize,
+}
+
+impl AudioStream<'_> {
+    pub fn sample_rate(&self) -> u32 {
+        self.sr
+    }
+
+    /// Seed the stream samples with, to reproduce it later.
+    pub fn seed(&self) -> u32 {
+        self.seed
+    }
+
+    /// The underlying token stream, e.g. to cancel it or read its stop reason.
+    pub fn tokens(&self) -> &dyn TokenSource<'_> {
+        self.tokens.as_ref()
+    }
+
+    /// Decodes every code held back, including the unfinished word block.
+    fn flush(&mut self) -> Result<Vec<f32>> {
+        for code in self.word_codes.drain(..) {
+            self.decoder.push(code);
+        }
+        self.decoder.finish()
+    }
+
+    /// The [`GenerationError`] ending a cancelled or timed out stream. Its
+    /// partial output holds the audio not yielded yet, so together with the
+    /// chunks already yielded it is everything generated; its metrics cover
+    /// the whole stream.
+    fn interrupted(&mut self, reason: StopReason) -> anyhow::Error {
+        let audio = match self.flush() {
+            Ok(audio) => audio,
+            Err(e) => return e,
+        };
+        let metrics = GenerationMetrics {
+            prompt_tokens: self.prompt_tokens,
+            generated_tokens: self.generated_tokens,
+            audio_codes: self.audio_codes,
+            prompt_eval_time: self.tokens.prompt_eval_time().as_secs_f64(),
+            generation_time: self.started.elapsed().as_secs_f64(),
+            audio_duration: (self.samples + audio.len()) as f64 / self.sr as f64,
+            ..Default::default()
+        }.finish();
+        let partial = ModelOutput::new(audio, self.sr, self.seed, Some(reason), metrics);
+        GenerationError::new(reason, partial).into()
+    }
+
+    fn chunk(&mut self, result: Result<Vec<f32>>) -> Option<Result<Vec<f32>>> {
+        match result {
+            Ok(chunk) if chunk.is_empty() => None,
+            Ok(chunk) => {
+                self.samples += chunk.len();
+                Some(Ok(chunk))
+            }
+            Err(e) => Some(Err(e)),
+        }
+    }
+}
+
+impl Iterator for AudioStream<'_> {
+    type Item = Result<Vec<f32>>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        while !self.finished {
+            match self.tokens.next() {
+                Some(Ok(token)) => {
+                    self.generated_tokens += 1;
+                    let token = token.0 as i64;
+                    if let Some(code) = self.prompt_processor.audio_code(token) {
+                        self.audio_codes += 1;
+                        self.word_codes.push(code);
+                    } else if token == self.code_end {
+                        for code in self.word_codes.drain(..) {
+                            self.decoder.push(code);
+                        }
+                        self.words_pending += 1;
+                        if self.words_pending >= self.words_per_chunk {
+                            self.words_pending = 0;
+                            let result = self.decoder.decode_ready();
+                            if let Some(chunk) = self.chunk(result) {
+                                return Some(chunk);
+                            }
+                        }
+                    }
+                }
+                Some(Err(e)) => {
+                    self.finished = true;
+                    return Some(Err(e));
+                }
+                None => {
+                    self.finished = true;
+                    match self.tokens.stop_reason() {
+                        Some(reason) if reason.is_interruption() => {
+                            return Some(Err(self.interrupted(reason)));
+                        }
+                        // A looping word is dropped; any other unfinished word is kept
+                        Some(StopReason::Loop) => {
+                            if self.abort_on_loop {
+                                return Some(Err(anyhow::anyhow!("Generation got stuck in a loop")));
+                            }
+                            self.word_codes.clear();
+                        }
+                        _ => {}
+                    }
+                    let result = self.flush();
+                    return self.chunk(result);
+                }
+            }
+        }
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backend::ScriptedModel;
+    use crate::dac_prompt::DacWord;
+    use crate::metadata::ModelMetadata;
+    use crate::model::CancellationToken;
+
+    fn test_config() -> GGUFModelConfig {
+        GGUFModelConfig {
+            model_path: String::new(),
+            language: "en".to_string(),
+            verbose: false,
+            max_seq_length: Some(4096),
+            n_gpu_layers: 0,
+            cache_speaker_prompt: false,
+            speaker_cache_dir: None,
+            n_contexts: 1,
+            context_timeout: None,
+            tuning: ModelTuning::default(),
+            prompt_version: None,
+            use_model_tokenizer: false,
+            lora_adapters: Vec::new(),
+        }
+    }
+
+    /// An interface whose backend replays the first `n_words` word blocks of
+    /// the built-in `male_1` speaker, then `<|audio_end|>`. The returned
+    /// speaker holds just those words.
+    fn scripted_interface(n_words: usize) -> (InterfaceGGUF, Speaker) {
+        let processor = PromptProcessor::new().unwrap();
+        let mut speaker: Speaker = serde_json::from_value(DEFAULT_SPEAKERS["en"]["male_1"].clone()).unwrap();
+        speaker.words.truncate(n_words);
+        let script = format!("{}\n<|audio_end|>", processor.create_audio_prompt(&speaker.words));
+        let tokens = processor.encode_prompt(&script).unwrap().iter().map(|&x| x as i32).collect();
+
+        let interface = InterfaceGGUF::with_backend(test_config(), Box::new(ScriptedModel::new(tokens))).unwrap();
+        (interface, speaker)
+    }
+
+    /// The speaker's words as text to generate.
+    fn text_of(speaker: &Speaker) -> String {
+        speaker.words.iter().map(|w| w.word.as_str()).collect::<Vec<_>>().join(" ")
+    }
+
+    fn block_on<T>(future: impl std::future::Future<Output = T>) -> T {
+        tokio::runtime::Runtime::new().unwrap().block_on(future)
+    }
+
+    /// Text to prompt to scripted tokens to audio, without a GGUF model.
+    #[test]
+    fn test_pipeline_with_scripted_backend() {
+        let (interface, speaker) = scripted_interface(2);
+        let text = format!("{} unspoken", text_of(&speaker));
+        let config = GenerationConfig { constrain: ConstraintMode::Layout, ..Default::default() };
+        let output = block_on(interface.generate(&text, None, &config)).unwrap();
+
+        let n_codes: usize = speaker.words.iter().map(|w| w.codes.len()).sum();
+        assert_eq!(output.stop_reason(), Some(StopReason::AudioEnd));
+        assert_eq!(output.metrics().audio_codes, n_codes);
+        assert!(output.metrics().audio_duration > 0.0);
+    }
+
+    /// `generate` blocks in place on a multi-threaded runtime, which would
+    /// panic on a current-thread one; both must work.
+    #[test]
+    fn test_generate_on_any_runtime() {
+        let (interface, _) = scripted_interface(0);
+        let config = GenerationConfig::default();
+        for mut builder in [tokio::runtime::Builder::new_current_thread(), tokio::runtime::Builder::new_multi_thread()] {
+            let runtime = builder.enable_all().build().unwrap();
+            assert!(runtime.block_on(interface.generate("hello", None, &config)).is_ok());
+        }
+    }
+
+    /// Default speakers are embedded JSON without a `name` field.
+    #[test]
+    fn test_load_default_speaker() {
+        let (interface, _) = scripted_interface(0);
+        for name in DEFAULT_SPEAKERS["en"].keys() {
+            let speaker = interface.load_default_speaker(name).unwrap();
+            let speaker: Speaker = serde_json::from_value(speaker).unwrap();
+            assert_eq!(speaker.language, "en");
+            assert!(speaker.name.is_empty());
+        }
+        assert!(interface.load_default_speaker("nobody").is_err());
+    }
+
+    /// The speaker's reference codes are in the prompt, not the output.
+    #[test]
+    fn test_output_excludes_speaker_codes() {
+        let (interface, speaker) = scripted_interface(1);
+        let speaker_json = DEFAULT_SPEAKERS["en"]["male_1"].clone();
+        let output = block_on(interface.generate(&text_of(&speaker), Some(&speaker_json), &GenerationConfig::default()))
+            .unwrap();
+
+        assert_eq!(output.metrics().audio_codes, speaker.words[0].codes.len());
+    }
+
+    #[test]
+    fn test_interrupted_generation_returns_partial_output() {
+        let (interface, _) = scripted_interface(0);
+
+        let cancel = CancellationToken::new();
+        cancel.cancel();
+        let config = GenerationConfig { cancel, ..Default::default() };
+        let error = block_on(interface.generate("hello", None, &config)).err().unwrap();
+        let error = error.downcast::<GenerationError>().unwrap();
+        assert!(matches!(error, GenerationError::Cancelled { .. }));
+        assert_eq!(error.partial().stop_reason(), Some(StopReason::Cancelled));
+
+        let config = GenerationConfig { deadline: Some(Instant::now()), ..Default::default() };
+        let error = block_on(interface.generate("hello", None, &config)).err().unwrap();
+        assert!(matches!(error.downcast::<GenerationError>().unwrap(), GenerationError::TimedOut { .. }));
+    }
+
+    #[test]
+    fn test_cancelled_stream_keeps_pending_audio() {
+        let (interface, speaker) = scripted_interface(2);
+        let config = GenerationConfig::default();
+        let mut stream = interface.generate_stream(&text_of(&speaker), None, &config, 1).unwrap();
+        let first = stream.next().unwrap().unwrap();
+        let sr = stream.sample_rate() as f64;
+        let seed = stream.seed();
+
+        config.cancel.cancel();
+        let error = stream.next().unwrap().unwrap_err().downcast::<GenerationError>().unwrap();
+        assert!(matches!(error, GenerationError::Cancelled { .. }));
+        let partial = error.partial();
+        // The first word's held-back tail is flushed into the error
+        assert!(partial.metrics().audio_duration > first.len() as f64 / sr);
+        assert_eq!(partial.metrics().audio_codes, speaker.words[0].codes.len());
+        assert_eq!(partial.seed(), seed);
+        assert!(stream.next().is_none());
+    }
+
+    #[test]
+    fn test_best_of_reports_every_candidate() {
+        let (interface, speaker) = scripted_interface(2);
+        let text = text_of(&speaker);
+        let config = GenerationConfig { seed: Some(7), best_of: 3, ..Default::default() };
+        let output = block_on(interface.generate(&text, None, &config)).unwrap();
+
+        let seeds: Vec<u32> = output.candidates().iter().map(|c| c.seed).collect();
+        assert_eq!(seeds, vec![7, 8, 9]);
+        assert!(output.candidates().iter().all(|c| c.words_spoken == 2 && c.word_count_match == 1.0));
+        // Ties keep the first candidate
+        assert_eq!(output.seed(), 7);
+
+        // Consecutive seeds skip the one llama.cpp reserves
+        let config = GenerationConfig { seed: Some(0xFFFF_FFFE), best_of: 2, ..Default::default() };
+        let output = block_on(interface.generate(&text, None, &config)).unwrap();
+        let seeds: Vec<u32> = output.candidates().iter().map(|c| c.seed).collect();
+        assert_eq!(seeds, vec![0xFFFF_FFFE, 0]);
+    }
+
+    #[test]
+    fn test_trace_covers_every_token() {
+        let (interface, speaker) = scripted_interface(2);
+        let config = GenerationConfig { trace_top_k: Some(3), ..Default::default() };
+        let output = block_on(interface.generate(&text_of(&speaker), None, &config)).unwrap();
+
+        assert_eq!(output.trace().len(), output.metrics().generated_tokens);
+        assert_eq!(output.words().len(), 2);
+        assert_eq!(output.words()[0].codes, speaker.words[0].codes.len());
+        assert_eq!(output.words()[1].word, speaker.words[1].word);
+        // The scripted backend reports no log-probabilities
+        assert_eq!(output.confidence(), None);
+    }
+
+    #[test]
+    fn test_batch_outputs_in_order() {
+        let (interface, speaker) = scripted_interface(2);
+        let first = speaker.words[0].word.clone();
+        let both = text_of(&speaker);
+        let config = GenerationConfig { seed: Some(3), ..Default::default() };
+        let outputs = block_on(interface.generate_batch(&[first.as_str(), both.as_str()], None, &config))
+            .unwrap()
+            .into_iter()
+            .collect::<Result<Vec<_>>>()
+            .unwrap();
+
+        let codes = |n: usize| speaker.words[..n].iter().map(|w| w.codes.len()).sum::<usize>();
+        assert_eq!(outputs.len(), 2);
+        assert_eq!(outputs[0].stop_reason(), Some(StopReason::AllWordsSpoken));
+        assert_eq!(outputs[0].metrics().audio_codes, codes(1));
+        assert_eq!(outputs[1].stop_reason(), Some(StopReason::AllWordsSpoken));
+        assert_eq!(outputs[1].metrics().audio_codes, codes(2));
+        assert!(outputs.iter().all(|output| output.seed() == 3));
+    }
+
+    /// OuteTTS 1.0 goes through its own prompt processor and the DAC decoder.
+    #[test]
+    fn test_dac_pipeline_with_scripted_backend() {
+        // build.rs only fetches the 0.2 assets
+        if ["tokenizer-1.0.json", "dac_decoder.onnx"].iter().any(|file| !std::path::Path::new("models").join(file).exists()) {
+            eprintln!("Skipping: the OuteTTS 1.0 tokenizer and DAC decoder are not in models/");
+            return;
+        }
+        let processor = DacPromptProcessor::new().unwrap();
+        let word = |word: &str| DacWord {
+            word: word.to_string(),
+            duration: 0.2,
+            c1: vec![1, 2],
+            c2: vec![3, 4],
+            features: Default::default(),
+        };
+        let script = format!(
+            "{}\n{}\n<|audio_end|>",
+            DacPromptProcessor::word_block(&word("hello")),
+            DacPromptProcessor::word_block(&word("world."))
+        );
+        let tokens = processor.encode_prompt(&script).unwrap().iter().map(|&x| x as i32).collect();
+
+        let config = GGUFModelConfig { prompt_version: Some(PromptVersion::V1_0), ..test_config() };
+        let interface = InterfaceGGUF::with_backend(config, Box::new(ScriptedModel::new(tokens))).unwrap();
+        let output = block_on(interface.generate("Hello world.", None, &GenerationConfig::default())).unwrap();
+
+        assert_eq!(output.stop_reason(), Some(StopReason::AllWordsSpoken));
+        assert_eq!(output.metrics().audio_codes, 8);
+        assert!(output.metrics().audio_duration > 0.0);
+        assert!(interface.generate_stream("Hello world.", None, &GenerationConfig::default(), 1).is_err());
+    }
+
+    #[test]
+    fn test_rejects_mismatched_vocab() {
+        let metadata = ModelMetadata {
+            name: Some("OuteTTS-0.2-500M".to_string()),
+            n_vocab: 1000,
+            ..Default::default()
+        };
+        let model = ScriptedModel::new(Vec::new()).with_metadata(metadata);
+        let error = InterfaceGGUF::with_backend(test_config(), Box::new(model)).err().unwrap();
+        assert!(error.to_string().contains("only has 1000 tokens"), "{}", error);
+    }
+}
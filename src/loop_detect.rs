@@ -0,0 +1,187 @@
+use llama_cpp_2::token::LlamaToken;
+use serde::{Deserialize, Serialize};
+use std::sync::Arc;
+
+use crate::grammar::{TokenClass, TokenClasses};
+use crate::model::{StopReason, StopRule};
+use crate::scoring::typical_duration;
+
+/// What the interface does when [`LoopDetector`] stops a generation.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+pub enum LoopPolicy {
+    /// Don't look for loops. The default, since the thresholds are only
+    /// rough estimates and truncation would cut audio without notice.
+    #[default]
+    Off,
+    /// Fail the request.
+    Abort,
+    /// Keep the audio up to the last complete word.
+    Truncate,
+    /// Continue from the last complete word with another seed and a higher
+    /// repetition penalty, truncating once the retries are used up.
+    Retry,
+}
+
+impl std::str::FromStr for LoopPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "off" => Ok(LoopPolicy::Off),
+            "abort" => Ok(LoopPolicy::Abort),
+            "truncate" => Ok(LoopPolicy::Truncate),
+            "retry" => Ok(LoopPolicy::Retry),
+            _ => Err(format!("Unknown loop policy '{}', expected off, abort, truncate or retry", s)),
+        }
+    }
+}
+
+/// Thresholds for [`LoopDetector`]. Audio runs at 75 codes per second.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct LoopDetection {
+    pub policy: LoopPolicy,
+    /// Longest repeating code pattern looked for
+    pub max_period: usize,
+    /// How many trailing codes of a word must repeat one pattern to count as a loop
+    pub min_loop_codes: usize,
+    /// Most codes a single word block may hold
+    pub max_word_codes: usize,
+    /// How far a word's time token may exceed its typical duration, as a factor
+    pub max_duration_ratio: f32,
+    /// Retries before `Retry` falls back to truncating
+    pub max_retries: usize,
+    /// Added to `repetition_penalty` on every retry
+    pub retry_penalty_step: f32,
+}
+
+impl Default for LoopDetection {
+    fn default() -> Self {
+        Self {
+            policy: LoopPolicy::Off,
+            max_period: 16,
+            min_loop_codes: 120,
+            max_word_codes: 300,
+            max_duration_ratio: 3.0,
+            max_retries: 2,
+            retry_penalty_step: 0.1,
+        }
+    }
+}
+
+/// Stops with [`StopReason::Loop`] when the word block being generated
+/// repeats a short code pattern, grows past `max_word_codes`, or declares a
+/// duration far beyond what its word needs. Only the current block is
+/// inspected, so everything before the last `<|code_end|>` is still good.
+pub struct LoopDetector {
+    classes: Arc<TokenClasses>,
+    settings: LoopDetection,
+    /// Typical duration of each expected word, in order
+    durations: Vec<f32>,
+    word: usize,
+    codes: Vec<i32>,
+}
+
+impl LoopDetector {
+    /// `words` are the words still to be spoken, in order.
+    pub fn new(classes: Arc<TokenClasses>, settings: &LoopDetection, words: &[String]) -> Self {
+        LoopDetector {
+            classes,
+            settings: settings.clone(),
+            durations: words.iter().map(|w| typical_duration(w)).collect(),
+            word: 0,
+            codes: Vec::new(),
+        }
+    }
+
+    fn duration_limit(&self) -> f32 {
+        // Past the expected words the model is already off script; judge it
+        // against the longest word
+        let typical = self.durations.get(self.word).copied()
+            .or_else(|| self.durations.iter().copied().reduce(f32::max))
+            .unwrap_or_else(|| typical_duration(""));
+        typical * self.settings.max_duration_ratio
+    }
+
+    fn repeats(&self) -> bool {
+        let n = self.settings.min_loop_codes;
+        if n == 0 || self.codes.len() < n {
+            return false;
+        }
+        let tail = &self.codes[self.codes.len() - n..];
+        (1..=self.settings.max_period.min(n - 1))
+            .any(|period| tail[period..].iter().zip(tail).all(|(a, b)| a == b))
+    }
+}
+
+impl StopRule for LoopDetector {
+    fn check(&mut self, token: LlamaToken) -> Option<StopReason> {
+        match self.classes.get(token) {
+            TokenClass::Time => {
+                let seconds = self.classes.time(token)?;
+                (seconds > self.duration_limit()).then_some(StopReason::Loop)
+            }
+            TokenClass::CodeStart => {
+                self.codes.clear();
+                None
+            }
+            TokenClass::AudioCode => {
+                self.codes.push(token.0);
+                (self.codes.len() > self.settings.max_word_codes || self.repeats())
+                    .then_some(StopReason::Loop)
+            }
+            TokenClass::CodeEnd => {
+                self.word += 1;
+                self.codes.clear();
+                None
+            }
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::prompt_processor::PromptProcessor;
+
+    fn detector(processor: &PromptProcessor, words: &[&str]) -> LoopDetector {
+        let classes = Arc::new(TokenClasses::new(processor).unwrap());
+        let words: Vec<String> = words.iter().map(|w| w.to_string()).collect();
+        LoopDetector::new(classes, &LoopDetection::default(), &words)
+    }
+
+    fn run(detector: &mut LoopDetector, processor: &PromptProcessor, text: &str) -> Option<StopReason> {
+        processor.encode_prompt(text).unwrap().into_iter()
+            .find_map(|id| detector.check(LlamaToken(id as i32)))
+    }
+
+    fn block(word: &str, time: &str, codes: impl Iterator<Item = usize>) -> String {
+        let codes: String = codes.map(|c| format!("<|{}|>", c)).collect();
+        format!("{}<|t_{}|><|code_start|>{}<|code_end|>\n", word, time, codes)
+    }
+
+    #[test]
+    fn test_loop_detection() {
+        let processor = PromptProcessor::new().unwrap();
+
+        // Varied codes at a plausible length pass
+        let mut d = detector(&processor, &["hello", "world"]);
+        let speech = block("hello", "0.40", (0..30).map(|i| i * 37 % 4096))
+            + &block("world", "0.45", (0..34).map(|i| i * 53 % 4096));
+        assert_eq!(run(&mut d, &processor, &speech), None);
+
+        // A short pattern repeated for the whole minimum span
+        let mut d = detector(&processor, &["hello"]);
+        let looping = block("hello", "0.40", (0..200).map(|i| [5, 6, 7][i % 3]));
+        assert_eq!(run(&mut d, &processor, &looping), Some(StopReason::Loop));
+
+        // A word block far longer than allowed
+        let mut d = detector(&processor, &["hello"]);
+        let long = block("hello", "0.40", (0..400).map(|i| i * 37 % 4096));
+        assert_eq!(run(&mut d, &processor, &long), Some(StopReason::Loop));
+
+        // A one-syllable word claiming several seconds
+        let mut d = detector(&processor, &["a"]);
+        assert_eq!(run(&mut d, &processor, &block("a", "5.00", 0..10)), Some(StopReason::Loop));
+    }
+}
@@ -1,14 +1,32 @@
 mod model;
+mod backend;
 mod prompt_processor;
+mod prompt_format;
 mod default_speakers;
 mod utils;
 mod audio_codec;
+mod dac_codec;
+mod dac_prompt;
 mod interface;
+mod context_pool;
+mod prompt_cache;
 mod types;
+mod grammar;
+mod loop_detect;
+mod scoring;
+mod metadata;
+mod tokenizer;
+mod trace;
 
 use clap::Parser;
 use anyhow::Result;
-use interface::{InterfaceGGUF, GGUFModelConfig};
+use interface::{InterfaceGGUF, GGUFModelConfig, GenerationError};
+use model::{
+    CancellationToken, ClassSampling, GenerationConfig, KvCacheType, LoraAdapter, ModelTuning, SamplingOverride,
+};
+use grammar::ConstraintMode;
+use loop_detect::{LoopDetection, LoopPolicy};
+use prompt_format::PromptVersion;
 
 #[derive(Parser, Debug)]
 #[command(author, version, about, long_about = None)]
@@ -29,6 +47,11 @@ struct Args {
     #[arg(long, default_value = "male_1")]
     speaker: String,
 
+    /// Speaker profile JSON to use instead of a built-in --speaker; the only
+    /// way to give OuteTTS 1.0 models a voice, since the built-ins are 0.x
+    #[arg(long)]
+    speaker_file: Option<String>,
+
     /// Output audio file path
     #[arg(long, default_value = "output.wav")]
     output: String,
@@ -41,9 +64,9 @@ struct Args {
     #[arg(long, default_value_t = 0.1)]
     temperature: f32,
 
-    /// Maximum sequence length
-    #[arg(long, default_value_t = 4096)]
-    max_length: usize,
+    /// Maximum sequence length (defaults to 4096, capped at the model's training context length)
+    #[arg(long)]
+    max_length: Option<usize>,
 
     /// Enable verbose output
     #[arg(long, default_value_t = false)]
@@ -52,6 +75,152 @@ struct Args {
     /// Repetition penalty
     #[arg(long, default_value_t = 1.1)]
     repetition_penalty: f32,
+
+    /// Number of recent tokens the penalties apply to (0 = disabled, -1 = whole context)
+    #[arg(long, default_value_t = 64, allow_negative_numbers = true)]
+    repeat_last_n: i32,
+
+    /// Frequency penalty
+    #[arg(long, default_value_t = 0.0)]
+    frequency_penalty: f32,
+
+    /// Presence penalty
+    #[arg(long, default_value_t = 0.0)]
+    presence_penalty: f32,
+
+    /// Top-k sampling (0 = disabled)
+    #[arg(long, default_value_t = 40)]
+    top_k: i32,
+
+    /// Top-p sampling (1.0 = disabled)
+    #[arg(long, default_value_t = 0.9)]
+    top_p: f32,
+
+    /// Min-p sampling (0.0 = disabled)
+    #[arg(long, default_value_t = 0.05)]
+    min_p: f32,
+
+    /// Locally typical sampling (1.0 = disabled)
+    #[arg(long, default_value_t = 1.0)]
+    typical_p: f32,
+
+    /// Temperature for duration tokens (defaults to --temperature). The
+    /// --time-* and --code-* flags cover only temperature and top-p for two
+    /// token classes; per-class penalties and the text and structural
+    /// classes are available through `GenerationConfig::class_sampling`
+    #[arg(long)]
+    time_temperature: Option<f32>,
+
+    /// Top-p for duration tokens (defaults to --top-p)
+    #[arg(long)]
+    time_top_p: Option<f32>,
+
+    /// Temperature for audio code tokens (defaults to --temperature)
+    #[arg(long)]
+    code_temperature: Option<f32>,
+
+    /// Top-p for audio code tokens (defaults to --top-p)
+    #[arg(long)]
+    code_top_p: Option<f32>,
+
+    /// Sampling seed for reproducible output (random if omitted; 4294967295 is reserved)
+    #[arg(long, value_parser = clap::value_parser!(u32).range(..0xFFFF_FFFF_i64))]
+    seed: Option<u32>,
+
+    /// OuteTTS prompt format: 0.1, 0.2, 0.3 or 1.0 (detected from the model if omitted)
+    #[arg(long)]
+    prompt_version: Option<PromptVersion>,
+
+    /// Tokenize prompts with the vocabulary embedded in the GGUF instead of models/tokenizer.json
+    #[arg(long, default_value_t = false)]
+    model_tokenizer: bool,
+
+    /// LoRA adapter to apply, as PATH or PATH:SCALE (repeatable)
+    #[arg(long = "lora")]
+    lora: Vec<LoraAdapter>,
+
+    /// Write every sampled token and word block confidence to this JSONL file (OuteTTS 0.x models only)
+    #[arg(long)]
+    trace: Option<String>,
+
+    /// Alternatives recorded per token in the trace
+    #[arg(long, default_value_t = 5)]
+    trace_top_k: usize,
+
+    /// Print generation metrics as JSON
+    #[arg(long, default_value_t = false)]
+    metrics_json: bool,
+
+    /// Constrain sampling to the audio token layout: off, layout, or words (also forces the word text)
+    #[arg(long, default_value = "off")]
+    constrain: ConstraintMode,
+
+    /// What to do when the model gets stuck repeating audio: off, abort, truncate or retry
+    #[arg(long, default_value = "off")]
+    on_loop: LoopPolicy,
+
+    /// Give up after this many seconds, keeping the audio generated so far
+    #[arg(long)]
+    timeout: Option<f64>,
+
+    /// Sample this many candidates with consecutive seeds and keep the best scored
+    #[arg(long, default_value_t = 1)]
+    best_of: usize,
+
+    /// Reuse the speaker's evaluated reference prompt across requests. The
+    /// reference then goes in its own turn ahead of the text instead of
+    /// sharing its turn, a layout the model was not trained on, so the
+    /// output can differ from an uncached run
+    #[arg(long, default_value_t = false)]
+    cache_speaker: bool,
+
+    /// Directory to persist cached speaker prompt states in
+    #[arg(long)]
+    speaker_cache_dir: Option<String>,
+
+    /// Number of contexts to create over the loaded model for parallel synthesis
+    #[arg(long, default_value_t = 1)]
+    contexts: usize,
+
+    /// Seconds to wait for a free context before failing (waits forever if omitted)
+    #[arg(long)]
+    context_timeout: Option<f64>,
+
+    /// Threads used for generation (llama.cpp default if omitted)
+    #[arg(long)]
+    threads: Option<i32>,
+
+    /// Threads used for prompt processing (llama.cpp default if omitted)
+    #[arg(long)]
+    threads_batch: Option<i32>,
+
+    /// Logical batch size for prompt processing
+    #[arg(long, default_value_t = 2048)]
+    batch_size: u32,
+
+    /// Physical batch size for prompt processing
+    #[arg(long, default_value_t = 512)]
+    ubatch_size: u32,
+
+    /// Read the model into memory instead of memory-mapping it
+    #[arg(long, default_value_t = false)]
+    no_mmap: bool,
+
+    /// Lock the model in RAM so it is never swapped out
+    #[arg(long, default_value_t = false)]
+    mlock: bool,
+
+    /// Enable flash attention
+    #[arg(long, default_value_t = false)]
+    flash_attention: bool,
+
+    /// KV cache storage type: f16 or q8_0 (q8_0 requires --flash-attention)
+    #[arg(long, default_value = "f16")]
+    kv_cache_type: KvCacheType,
+
+    /// Sequences each context can decode together in batched generation
+    #[arg(long, default_value_t = 1)]
+    parallel: u32,
 }
 
 #[tokio::main]
@@ -70,6 +239,24 @@ async fn main() -> Result<()> {
         verbose: args.verbose,
         n_gpu_layers: args.gpu_layers,
         max_seq_length: args.max_length,
+        cache_speaker_prompt: args.cache_speaker || args.speaker_cache_dir.is_some(),
+        speaker_cache_dir: args.speaker_cache_dir,
+        n_contexts: args.contexts,
+        context_timeout: args.context_timeout.map(std::time::Duration::from_secs_f64),
+        tuning: ModelTuning {
+            n_threads: args.threads,
+            n_threads_batch: args.threads_batch,
+            n_batch: args.batch_size,
+            n_ubatch: args.ubatch_size,
+            use_mmap: !args.no_mmap,
+            use_mlock: args.mlock,
+            flash_attention: args.flash_attention,
+            kv_cache_type: args.kv_cache_type,
+            n_seq_max: args.parallel,
+        },
+        prompt_version: args.prompt_version,
+        use_model_tokenizer: args.model_tokenizer,
+        lora_adapters: args.lora,
     };
 
     // First validate that the speaker exists
@@ -78,7 +265,9 @@ async fn main() -> Result<()> {
     }
     
     // Pre-validate speaker before model initialization
-    InterfaceGGUF::validate_speaker(&config.language, &args.speaker)?;
+    if args.speaker_file.is_none() {
+        InterfaceGGUF::validate_speaker(&config.language, &args.speaker)?;
+    }
 
     // Initialize interface (including model) only after speaker validation
     if config.verbose {
@@ -86,22 +275,102 @@ async fn main() -> Result<()> {
     }
     let interface = InterfaceGGUF::new(config).await?;
 
-    // Load speaker after validation
-    let speaker = interface.load_default_speaker(&args.speaker)?;
+    // Load speaker after validation. The built-in speakers are in the 0.x
+    // format, so 1.0 models speak without a reference voice unless given a file
+    let speaker = match &args.speaker_file {
+        Some(path) => Some(interface.load_speaker(path)?),
+        None if interface.supports_default_speakers() => Some(interface.load_default_speaker(&args.speaker)?),
+        None => {
+            if args.verbose {
+                println!("No built-in speakers for this model; generating without a reference voice");
+            }
+            None
+        }
+    };
+
+    // Ctrl-C stops generation but still saves the partial audio
+    let cancel = CancellationToken::new();
+    let on_ctrl_c = cancel.clone();
+    tokio::spawn(async move {
+        if tokio::signal::ctrl_c().await.is_ok() {
+            on_ctrl_c.cancel();
+        }
+    });
+
+    let generation_config = GenerationConfig {
+        temperature: args.temperature,
+        repetition_penalty: args.repetition_penalty,
+        max_length: args.max_length.unwrap_or(interface.max_seq_length()),
+        penalty_last_n: args.repeat_last_n,
+        frequency_penalty: args.frequency_penalty,
+        presence_penalty: args.presence_penalty,
+        top_k: args.top_k,
+        top_p: args.top_p,
+        min_p: args.min_p,
+        typical_p: args.typical_p,
+        seed: args.seed,
+        constrain: args.constrain,
+        loop_detection: LoopDetection { policy: args.on_loop, ..Default::default() },
+        best_of: args.best_of,
+        class_sampling: ClassSampling {
+            time: SamplingOverride { temperature: args.time_temperature, top_p: args.time_top_p, ..Default::default() },
+            audio_code: SamplingOverride { temperature: args.code_temperature, top_p: args.code_top_p, ..Default::default() },
+            ..Default::default()
+        },
+        trace_top_k: args.trace.as_ref().map(|_| args.trace_top_k),
+        lora: None,
+        cancel,
+        deadline: args.timeout.map(|secs| std::time::Instant::now() + std::time::Duration::from_secs_f64(secs)),
+    };
 
-    let output = interface.generate(
+    let output = match interface.generate(
         &args.text,
-        Some(&speaker),
-        Some(args.temperature),
-        Some(args.repetition_penalty),
-        Some(args.max_length),
-    ).await?;
+        speaker.as_ref(),
+        &generation_config,
+    ).await {
+        Ok(output) => output,
+        Err(e) => {
+            let interrupted = e.downcast::<GenerationError>()?;
+            interrupted.partial().save(&args.output)?;
+            if let Some(path) = &args.trace {
+                let partial = interrupted.partial();
+                trace::write_jsonl(path, partial.trace(), partial.words())?;
+            }
+            anyhow::bail!("{}; partial audio saved to {}", interrupted, args.output);
+        }
+    };
 
     // Save to file
     output.save(&args.output)?;
+    if let Some(path) = &args.trace {
+        trace::write_jsonl(path, output.trace(), output.words())?;
+    }
     
+    let metrics = output.metrics();
+    if args.metrics_json {
+        println!("{}", serde_json::to_string(metrics)?);
+        if output.candidates().len() > 1 {
+            println!("{}", serde_json::to_string(output.candidates())?);
+        }
+    } else if args.verbose {
+        println!("Prompt tokens:     {}", metrics.prompt_tokens);
+        println!("Generated tokens:  {}", metrics.generated_tokens);
+        println!("Audio codes:       {}", metrics.audio_codes);
+        println!("Prompt eval time:  {:.3}s", metrics.prompt_eval_time);
+        println!("Generation time:   {:.3}s ({:.1} tokens/s)", metrics.generation_time, metrics.tokens_per_second);
+        println!("Decode time:       {:.3}s", metrics.decode_time);
+        println!("Audio duration:    {:.3}s", metrics.audio_duration);
+        println!("Real-time factor:  {:.3}", metrics.real_time_factor);
+        if metrics.loop_retries > 0 {
+            println!("Loop retries:      {}", metrics.loop_retries);
+        }
+        if let Some(confidence) = output.confidence() {
+            println!("Confidence:        {:.3}", confidence);
+        }
+    }
+
     if args.verbose {
-        println!("Audio saved to: {}", args.output);
+        println!("Audio saved to: {} (seed {})", args.output, output.seed());
     }
     
     Ok(())
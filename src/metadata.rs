@@ -0,0 +1,94 @@
+use anyhow::Result;
+use llama_cpp_2::model::LlamaModel;
+use serde::Serialize;
+
+use crate::prompt_format::PromptVersion;
+
+/// What a GGUF file says about itself, read once at load time.
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct ModelMetadata {
+    /// `general.architecture`, e.g. `qwen2` or `llama`
+    pub architecture: Option<String>,
+    /// `general.name`
+    pub name: Option<String>,
+    pub n_vocab: usize,
+    /// Context length the model was trained with
+    pub context_length: Option<usize>,
+    pub chat_template: Option<String>,
+}
+
+impl ModelMetadata {
+    pub fn read(model: &LlamaModel) -> Self {
+        let context_length = model.n_ctx_train() as usize;
+        ModelMetadata {
+            architecture: model.meta_val_str("general.architecture").ok(),
+            name: model.meta_val_str("general.name").ok(),
+            n_vocab: model.n_vocab().max(0) as usize,
+            context_length: (context_length > 0).then_some(context_length),
+            chat_template: model.meta_val_str("tokenizer.chat_template").ok(),
+        }
+    }
+
+    /// Prompt format implied by the model name, if it names an OuteTTS release.
+    pub fn prompt_version(&self) -> Option<PromptVersion> {
+        self.name.as_deref().and_then(PromptVersion::detect)
+    }
+
+    /// Checks that every `(piece, id)` pair from the prompt tokenizer exists
+    /// in the model's vocabulary under the same id. `piece_of` looks up the
+    /// model's text for an id; when it returns `None` only the vocabulary
+    /// size is checked.
+    pub fn check_vocab(
+        &self,
+        expected: &[(String, i64)],
+        piece_of: impl Fn(i32) -> Option<String>,
+    ) -> Result<()> {
+        let model = self.name.as_deref().unwrap_or("the model");
+        for (piece, id) in expected {
+            if *id < 0 || *id as usize >= self.n_vocab {
+                anyhow::bail!(
+                    "{} has id {} in the tokenizer but {} only has {} tokens; is it an OuteTTS model?",
+                    piece, id, model, self.n_vocab
+                );
+            }
+            if let Some(actual) = piece_of(*id as i32) {
+                if actual != *piece {
+                    anyhow::bail!(
+                        "Token {} is {:?} in {} but {:?} in the tokenizer; the model and tokenizer do not match",
+                        id, actual, model, piece
+                    );
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn metadata(n_vocab: usize) -> ModelMetadata {
+        ModelMetadata {
+            name: Some("OuteTTS-0.2-500M".to_string()),
+            n_vocab,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_check_vocab() {
+        let expected = vec![("<|audio_end|>".to_string(), 10), ("<|code_end|>".to_string(), 11)];
+        let vocab = |id: i32| Some(match id {
+            10 => "<|audio_end|>".to_string(),
+            11 => "<|code_end|>".to_string(),
+            _ => String::new(),
+        });
+
+        assert!(metadata(12).check_vocab(&expected, vocab).is_ok());
+        assert!(metadata(11).check_vocab(&expected, vocab).is_err());
+        assert!(metadata(12).check_vocab(&expected, |_| Some("hello".to_string())).is_err());
+        assert!(metadata(12).check_vocab(&expected, |_| None).is_ok());
+        assert_eq!(metadata(12).prompt_version(), Some(PromptVersion::V0_2));
+    }
+}
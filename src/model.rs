@@ -1,57 +1,450 @@
 use anyhow::Result;
-use llama_cpp_2::context::params::LlamaContextParams;
+use llama_cpp_2::context::params::{KvCacheType as LlamaKvCacheType, LlamaContextParams};
+use llama_cpp_2::context::LlamaContext;
+use llama_cpp_2::llama_batch::LlamaBatch;
 use llama_cpp_2::llama_backend::LlamaBackend;
 use llama_cpp_2::model::params::LlamaModelParams;
-use llama_cpp_2::model::LlamaModel;
+use llama_cpp_2::model::{LlamaLoraAdapter, LlamaModel, Special};
 use llama_cpp_2::sampling::LlamaSampler;
+use llama_cpp_2::token::data_array::LlamaTokenDataArray;
 use llama_cpp_2::token::LlamaToken;
 use serde::{Deserialize, Serialize};
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hasher};
 use std::num::NonZeroU32;
-use std::path::Path;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::{Arc, Mutex, Once};
+use std::time::{Duration, Instant};
 use lazy_static::lazy_static;
 
+use crate::backend::{LanguageModel, TokenSource};
+use crate::grammar::ConstraintMode;
+use crate::loop_detect::LoopDetection;
+use crate::interface::GGUFModelConfig;
+use crate::metadata::ModelMetadata;
+use crate::context_pool::{ContextPool, PooledContext};
+use crate::prompt_cache::PromptCache;
+use crate::tokenizer::{GgufTokenizer, PromptTokenizer};
+
 static INIT: Once = Once::new();
 
 lazy_static! {
     static ref BACKEND: Arc<Mutex<Option<Arc<LlamaBackend>>>> = Arc::new(Mutex::new(None));
 }
 
+/// llama.cpp's sentinel for "pick a random seed".
+const LLAMA_DEFAULT_SEED: u32 = 0xFFFF_FFFF;
+
 const DEFAULT_MODEL_PATH: &str = "models/OuteTTS-0.2-500M-FP16.gguf";
 
+/// Context size when the caller does not set one, unless the model was
+/// trained with a shorter context.
+const DEFAULT_CONTEXT_SIZE: usize = 4096;
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct GenerationConfig {
     pub temperature: f32,
     pub repetition_penalty: f32,
     pub max_length: usize,
+    /// Number of most recent tokens the penalties look at (0 disables them, -1 means the whole context)
+    pub penalty_last_n: i32,
+    pub frequency_penalty: f32,
+    pub presence_penalty: f32,
+    /// Keep only the k most likely tokens (0 disables)
+    pub top_k: i32,
+    /// Nucleus sampling threshold (1.0 disables)
+    pub top_p: f32,
+    /// Drop tokens below this fraction of the top token's probability (0.0 disables)
+    pub min_p: f32,
+    /// Locally typical sampling threshold (1.0 disables)
+    pub typical_p: f32,
+    /// Seed for the final distribution sampler; `None` picks a random one
+    pub seed: Option<u32>,
+    /// Restrict sampling to the OuteTTS audio layout (applied by the interface)
+    pub constrain: ConstraintMode,
+    /// When a generation counts as stuck and what to do then; off unless enabled (applied by the interface)
+    pub loop_detection: LoopDetection,
+    /// Candidates to sample, with consecutive seeds, keeping the best scored (applied by the interface)
+    pub best_of: usize,
+    /// Settings that replace the ones above for particular token classes
+    pub class_sampling: ClassSampling,
+    /// Record each token's log-probability and this many top alternatives
+    /// (applied by the interface); `None` disables the trace
+    pub trace_top_k: Option<usize>,
+    /// LoRA adapters for this request; `None` uses the model's configured ones.
+    /// Any other set bypasses the prompt cache, and every adapter named is
+    /// kept loaded until the model is dropped.
+    pub lora: Option<Vec<LoraAdapter>>,
+    /// Stops the request early once cancelled
+    #[serde(skip)]
+    pub cancel: CancellationToken,
+    /// Stops the request early once passed
+    #[serde(skip)]
+    pub deadline: Option<Instant>,
 }
 
 impl Default for GenerationConfig {
     fn default() -> Self {
+        // Values recommended by upstream OuteTTS
         Self {
             temperature: 0.1,
             repetition_penalty: 1.1,
             max_length: 4096,
+            penalty_last_n: 64,
+            frequency_penalty: 0.0,
+            presence_penalty: 0.0,
+            top_k: 40,
+            top_p: 0.9,
+            min_p: 0.05,
+            typical_p: 1.0,
+            seed: None,
+            constrain: ConstraintMode::Off,
+            loop_detection: LoopDetection::default(),
+            best_of: 1,
+            class_sampling: ClassSampling::default(),
+            trace_top_k: None,
+            lora: None,
+            cancel: CancellationToken::default(),
+            deadline: None,
+        }
+    }
+}
+
+impl GenerationConfig {
+    /// Returns a copy with `seed` filled in, drawing a random seed when none
+    /// was given, so the caller can record which one was used.
+    pub fn with_resolved_seed(&self) -> Self {
+        let seed = self.seed.unwrap_or_else(|| {
+            // Lower 32 bits of a randomly keyed hash; never the "random" sentinel
+            let seed = RandomState::new().build_hasher().finish() as u32;
+            seed.min(LLAMA_DEFAULT_SEED - 1)
+        });
+        Self { seed: Some(seed), ..self.clone() }
+    }
+
+    /// Seed for the `retry`-th loop retry of this request, or `None` when it
+    /// has no seed. Drawn from a different stream than the consecutive seeds
+    /// of `best_of` candidates, so a retry never re-samples another candidate.
+    pub fn retry_seed(&self, retry: u32) -> Option<u32> {
+        let seed = self.seed?;
+        // SplitMix64 finalizer over (seed, retry)
+        let mut z = (((seed as u64) << 32) | retry as u64).wrapping_add(0x9E37_79B9_7F4A_7C15);
+        z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+        z ^= z >> 31;
+        Some(((z >> 32) as u32).min(LLAMA_DEFAULT_SEED - 1))
+    }
+
+    /// Seed of the `i`-th `best_of` candidate, or `None` when the request has
+    /// no seed: consecutive seeds from the request's, wrapping past the
+    /// reserved [`LLAMA_DEFAULT_SEED`] to 0.
+    pub fn candidate_seed(&self, i: u32) -> Option<u32> {
+        let seed = self.seed?;
+        Some(((seed as u64 + i as u64) % LLAMA_DEFAULT_SEED as u64) as u32)
+    }
+
+    /// Rejects a seed llama.cpp would silently replace with a random one.
+    pub fn validate(&self) -> Result<()> {
+        if self.seed == Some(LLAMA_DEFAULT_SEED) {
+            return Err(anyhow::anyhow!(
+                "Seed {} means \"random\" to llama.cpp and cannot be reproduced; pick another",
+                LLAMA_DEFAULT_SEED
+            ));
+        }
+        Ok(())
+    }
+
+    /// Settings for tokens of `class`, or `None` when it has no overrides.
+    pub fn for_class(&self, class: SamplingClass) -> Option<Self> {
+        let overrides = self.class_sampling.get(class);
+        if overrides.is_empty() {
+            return None;
+        }
+        Some(Self {
+            temperature: overrides.temperature.unwrap_or(self.temperature),
+            top_p: overrides.top_p.unwrap_or(self.top_p),
+            repetition_penalty: overrides.repetition_penalty.unwrap_or(self.repetition_penalty),
+            frequency_penalty: overrides.frequency_penalty.unwrap_or(self.frequency_penalty),
+            presence_penalty: overrides.presence_penalty.unwrap_or(self.presence_penalty),
+            ..self.clone()
+        })
+    }
+
+    /// Whether tokens need their log-probability, for scoring `best_of`
+    /// candidates or for the trace. Computing it is a pass over the whole
+    /// vocabulary per token, so it is skipped otherwise.
+    pub fn needs_logprobs(&self) -> bool {
+        self.best_of > 1 || self.trace_top_k.is_some()
+    }
+
+    /// `Cancelled` or `TimedOut` once the request should stop.
+    pub fn interruption(&self) -> Option<StopReason> {
+        if self.cancel.is_cancelled() {
+            Some(StopReason::Cancelled)
+        } else if self.deadline.is_some_and(|deadline| Instant::now() >= deadline) {
+            Some(StopReason::TimedOut)
+        } else {
+            None
+        }
+    }
+}
+
+/// Kinds of token that can be sampled with their own settings.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SamplingClass {
+    /// Letters and punctuation spelling a word
+    Text,
+    /// Duration tokens, `<|t_x.xx|>`
+    Time,
+    /// Acoustic codes
+    AudioCode,
+    /// Layout tokens such as `<|code_start|>`, newlines and `<|audio_end|>`
+    Structural,
+}
+
+impl SamplingClass {
+    pub const ALL: [SamplingClass; 4] = [
+        SamplingClass::Text,
+        SamplingClass::Time,
+        SamplingClass::AudioCode,
+        SamplingClass::Structural,
+    ];
+}
+
+/// Sampling settings for one [`SamplingClass`]; `None` keeps the request's
+/// own value.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct SamplingOverride {
+    pub temperature: Option<f32>,
+    pub top_p: Option<f32>,
+    pub repetition_penalty: Option<f32>,
+    pub frequency_penalty: Option<f32>,
+    pub presence_penalty: Option<f32>,
+}
+
+impl SamplingOverride {
+    pub fn is_empty(&self) -> bool {
+        *self == Self::default()
+    }
+}
+
+/// Per-class sampling settings, e.g. near-greedy durations with some
+/// temperature on the codes. Which class the next token falls in is decided
+/// by the stream's [`SamplingClassifier`].
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct ClassSampling {
+    pub text: SamplingOverride,
+    pub time: SamplingOverride,
+    pub audio_code: SamplingOverride,
+    pub structural: SamplingOverride,
+}
+
+impl ClassSampling {
+    pub fn get(&self, class: SamplingClass) -> &SamplingOverride {
+        match class {
+            SamplingClass::Text => &self.text,
+            SamplingClass::Time => &self.time,
+            SamplingClass::AudioCode => &self.audio_code,
+            SamplingClass::Structural => &self.structural,
         }
     }
+
+    pub fn is_empty(&self) -> bool {
+        SamplingClass::ALL.iter().all(|&class| self.get(class).is_empty())
+    }
+}
+
+/// Cancels a request from another thread or task. Clones share the flag,
+/// so every stream started for the request sees it.
+#[derive(Debug, Clone, Default)]
+pub struct CancellationToken(Arc<AtomicBool>);
+
+impl CancellationToken {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn cancel(&self) {
+        self.0.store(true, Ordering::Relaxed);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.0.load(Ordering::Relaxed)
+    }
+
+    /// The underlying flag, as handed out by [`TokenSource::cancel_flag`].
+    pub fn flag(&self) -> Arc<AtomicBool> {
+        self.0.clone()
+    }
+}
+
+/// A GGUF LoRA adapter and the scale it is applied with.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct LoraAdapter {
+    pub path: PathBuf,
+    pub scale: f32,
+}
+
+impl std::str::FromStr for LoraAdapter {
+    type Err = String;
+
+    /// Parses `PATH` or `PATH:SCALE`; the scale defaults to 1.0.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s.is_empty() {
+            return Err("LoRA adapter path is empty".to_string());
+        }
+        if let Some((path, scale)) = s.rsplit_once(':') {
+            if let Ok(scale) = scale.parse() {
+                return Ok(LoraAdapter { path: path.into(), scale });
+            }
+        }
+        Ok(LoraAdapter { path: s.into(), scale: 1.0 })
+    }
+}
+
+/// An adapter loaded over the model, shared by every context.
+struct LoadedAdapter {
+    path: PathBuf,
+    adapter: LlamaLoraAdapter,
+}
+
+// SAFETY: adapters are only touched while holding `GGUFModel::adapters`,
+// and llama.cpp only reads their weights once applied.
+unsafe impl Send for LoadedAdapter {}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum KvCacheType {
+    F16,
+    Q8_0,
+}
+
+impl std::str::FromStr for KvCacheType {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "f16" => Ok(KvCacheType::F16),
+            "q8_0" => Ok(KvCacheType::Q8_0),
+            _ => Err(format!("Unknown KV cache type '{}', expected f16 or q8_0", s)),
+        }
+    }
+}
+
+impl From<KvCacheType> for LlamaKvCacheType {
+    fn from(value: KvCacheType) -> Self {
+        match value {
+            KvCacheType::F16 => LlamaKvCacheType::F16,
+            KvCacheType::Q8_0 => LlamaKvCacheType::Q8_0,
+        }
+    }
+}
+
+/// llama.cpp model and context knobs. The defaults match llama.cpp's own.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ModelTuning {
+    /// Threads used for single-token generation (`None` = llama.cpp default)
+    pub n_threads: Option<i32>,
+    /// Threads used for prompt/batch processing (`None` = llama.cpp default)
+    pub n_threads_batch: Option<i32>,
+    /// Logical batch size: most tokens submitted in one decode call
+    pub n_batch: u32,
+    /// Physical batch size: most tokens computed at once
+    pub n_ubatch: u32,
+    pub use_mmap: bool,
+    pub use_mlock: bool,
+    pub flash_attention: bool,
+    /// Storage type for the K and V caches
+    pub kv_cache_type: KvCacheType,
+    /// Sequences a context decodes together in [`GGUFModel::generate_batch`];
+    /// they share its KV cache
+    pub n_seq_max: u32,
+}
+
+impl Default for ModelTuning {
+    fn default() -> Self {
+        Self {
+            n_threads: None,
+            n_threads_batch: None,
+            n_batch: 2048,
+            n_ubatch: 512,
+            use_mmap: true,
+            use_mlock: false,
+            flash_attention: false,
+            kv_cache_type: KvCacheType::F16,
+            n_seq_max: 1,
+        }
+    }
+}
+
+impl ModelTuning {
+    pub fn validate(&self) -> Result<()> {
+        for (name, threads) in [("n_threads", self.n_threads), ("n_threads_batch", self.n_threads_batch)] {
+            if let Some(threads) = threads {
+                if threads < 1 {
+                    return Err(anyhow::anyhow!("{} must be at least 1, got {}", name, threads));
+                }
+            }
+        }
+        if self.n_batch == 0 || self.n_ubatch == 0 {
+            return Err(anyhow::anyhow!("n_batch and n_ubatch must be greater than zero"));
+        }
+        if self.n_seq_max == 0 {
+            return Err(anyhow::anyhow!("n_seq_max must be at least 1"));
+        }
+        if self.n_ubatch > self.n_batch {
+            return Err(anyhow::anyhow!(
+                "n_ubatch ({}) cannot be larger than n_batch ({})",
+                self.n_ubatch,
+                self.n_batch
+            ));
+        }
+        if self.kv_cache_type != KvCacheType::F16 && !self.flash_attention {
+            return Err(anyhow::anyhow!(
+                "A quantized KV cache ({:?}) requires flash attention to be enabled",
+                self.kv_cache_type
+            ));
+        }
+        Ok(())
+    }
 }
 
 pub struct GGUFModel {
+    contexts: ContextPool,
+    /// Every adapter loaded so far, whether or not it is currently applied.
+    /// Adapters stay loaded for the model's lifetime, so each distinct path
+    /// requests name keeps its weights in memory until the model is dropped.
+    adapters: Mutex<Vec<LoadedAdapter>>,
+    /// Adapters for requests that do not pick their own
+    lora: Vec<LoraAdapter>,
     model: Arc<LlamaModel>,
-    context: Arc<Mutex<llama_cpp_2::context::LlamaContext<'static>>>,
     backend: Arc<LlamaBackend>,
+    prompt_cache: PromptCache,
+    metadata: ModelMetadata,
+    n_ctx: usize,
+    n_seq_max: usize,
 }
 
 impl GGUFModel {
     pub fn default() -> Result<Self> {
-        Self::new(DEFAULT_MODEL_PATH, 1, 4096)
+        Self::new(DEFAULT_MODEL_PATH, 1, Some(4096), 1, &ModelTuning::default(), &[])
     }
 
+    /// Loads the model once and creates `n_contexts` contexts over it, so up
+    /// to that many generations can run in parallel. `max_seq_length`
+    /// defaults to 4096 tokens and is capped at the context length in the
+    /// model's metadata. `lora` is loaded up front and applied to requests
+    /// that do not choose their own adapters.
     pub fn new(
         model_path: impl AsRef<Path>,
         n_gpu_layers: u32,
-        max_seq_length: usize,
+        max_seq_length: Option<usize>,
+        n_contexts: usize,
+        tuning: &ModelTuning,
+        lora: &[LoraAdapter],
     ) -> Result<Self> {
+        tuning.validate()?;
+
         let backend = {
             let mut backend_guard = BACKEND.lock().unwrap();
             if let Some(ref backend) = *backend_guard {
@@ -65,60 +458,1232 @@ impl GGUFModel {
         };
 
         let model_params = LlamaModelParams::default()
-            .with_n_gpu_layers(n_gpu_layers);
-        
-        let model = Arc::new(LlamaModel::load_from_file(&backend, model_path, &model_params)?);
+            .with_n_gpu_layers(n_gpu_layers)
+            .with_use_mmap(tuning.use_mmap)
+            .with_use_mlock(tuning.use_mlock);
         
+        let model_path = model_path.as_ref();
+        let model = Arc::new(
+            LlamaModel::load_from_file(&backend, model_path, &model_params)
+                .map_err(|e| anyhow::anyhow!("Failed to load {}: {}", model_path.display(), e))?
+        );
+        let metadata = ModelMetadata::read(&model);
+        // Cached prompt states on disk are only valid for this exact file
+        // under these exact default adapters (only they are ever cached)
+        let file_identity = |path: &Path| format!(
+            "{}|{}",
+            std::fs::canonicalize(path).unwrap_or_else(|_| path.to_path_buf()).display(),
+            std::fs::metadata(path).map(|m| m.len()).unwrap_or_default(),
+        );
+        let mut identity = format!(
+            "{}|{}|{}|{}",
+            file_identity(model_path),
+            metadata.name.as_deref().unwrap_or_default(),
+            metadata.architecture.as_deref().unwrap_or_default(),
+            metadata.n_vocab,
+        );
+        for adapter in lora {
+            identity.push_str(&format!("|lora={}:{}", file_identity(&adapter.path), adapter.scale));
+        }
+
+        // Every pooled context allocates a KV cache of this size, so the
+        // training context only caps it and never enlarges the default
+        let max_seq_length = match (max_seq_length, metadata.context_length) {
+            (Some(requested), Some(trained)) if requested > trained => {
+                eprintln!(
+                    "Warning: max_seq_length {} exceeds the model's training context; using {} tokens",
+                    requested, trained
+                );
+                trained
+            }
+            (Some(requested), _) => requested,
+            (None, trained) => trained.map_or(DEFAULT_CONTEXT_SIZE, |trained| trained.min(DEFAULT_CONTEXT_SIZE)),
+        };
         let ctx_size = NonZeroU32::new(max_seq_length as u32)
             .ok_or_else(|| anyhow::anyhow!("Context size must be greater than zero"))?;
         
-        let ctx_params = LlamaContextParams::default()
-            .with_n_ctx(Some(ctx_size));
+        let mut ctx_params = LlamaContextParams::default()
+            .with_n_ctx(Some(ctx_size))
+            // llama.cpp never submits more than n_ctx tokens at once anyway
+            .with_n_batch(tuning.n_batch.min(ctx_size.get()))
+            .with_n_ubatch(tuning.n_ubatch.min(ctx_size.get()))
+            .with_flash_attention(tuning.flash_attention)
+            .with_type_k(tuning.kv_cache_type.into())
+            .with_type_v(tuning.kv_cache_type.into())
+            .with_n_seq_max(tuning.n_seq_max);
+        if let Some(n_threads) = tuning.n_threads {
+            ctx_params = ctx_params.with_n_threads(n_threads);
+        }
+        if let Some(n_threads_batch) = tuning.n_threads_batch {
+            ctx_params = ctx_params.with_n_threads_batch(n_threads_batch);
+        }
             
-        let context = unsafe {
-            Arc::new(Mutex::new(std::mem::transmute::<
-                llama_cpp_2::context::LlamaContext<'_>,
-                llama_cpp_2::context::LlamaContext<'static>
-            >(model.new_context(&backend, ctx_params)?)))
+        let contexts = ContextPool::new(&model, &backend, &ctx_params, n_contexts)?;
+
+        let model = Self {
+            contexts,
+            adapters: Mutex::new(Vec::new()),
+            lora: lora.to_vec(),
+            model,
+            backend,
+            prompt_cache: PromptCache::new(identity, None),
+            metadata,
+            n_ctx: max_seq_length,
+            n_seq_max: tuning.n_seq_max as usize,
         };
+        // Fail on a bad adapter now rather than on the first request
+        model.load_adapters(lora)?;
+        Ok(model)
+    }
 
-        Ok(Self { model, context, backend })
+    /// Loads any of `lora` not loaded yet, returning the guarded list.
+    fn load_adapters(&self, lora: &[LoraAdapter]) -> Result<std::sync::MutexGuard<'_, Vec<LoadedAdapter>>> {
+        let mut loaded = self.adapters.lock().unwrap();
+        for wanted in lora {
+            if loaded.iter().any(|adapter| adapter.path == wanted.path) {
+                continue;
+            }
+            let adapter = self.model.lora_adapter_init(&wanted.path)
+                .map_err(|e| anyhow::anyhow!("Failed to load LoRA adapter {}: {}", wanted.path.display(), e))?;
+            loaded.push(LoadedAdapter { path: wanted.path.clone(), adapter });
+        }
+        Ok(loaded)
     }
 
-    pub fn generate(
+    /// Makes `lora` exactly the set of adapters applied to `context`,
+    /// loading any new ones. Contexts already in that state are left alone.
+    fn apply_lora(&self, context: &mut PooledContext<'_>, lora: &[LoraAdapter]) -> Result<()> {
+        if context.lora() == lora {
+            return Ok(());
+        }
+
+        let mut loaded = self.load_adapters(lora)?;
+        // Kept in step with the context, so that a failure part way still
+        // records what is applied and the next request fixes it up
+        let mut applied = context.lora().to_vec();
+        let result = context.with_mut(|context| -> Result<()> {
+            while let Some(old) = applied.last() {
+                if let Some(adapter) = loaded.iter_mut().find(|adapter| adapter.path == old.path) {
+                    context.lora_adapter_remove(&mut adapter.adapter)?;
+                }
+                applied.pop();
+            }
+            for new in lora {
+                let adapter = loaded.iter_mut()
+                    .find(|adapter| adapter.path == new.path)
+                    .expect("adapter was just loaded");
+                context.lora_adapter_set(&mut adapter.adapter, new.scale)?;
+                applied.push(new.clone());
+            }
+            Ok(())
+        });
+        context.set_lora(applied);
+        result
+    }
+
+    /// Whether `config` runs with the configured adapters, under which
+    /// cached prompt states were computed.
+    fn uses_default_lora(&self, config: &GenerationConfig) -> bool {
+        config.lora.as_ref().is_none_or(|lora| *lora == self.lora)
+    }
+
+    /// Checks out a context with the adapters `config` asks for applied,
+    /// giving up early if the request is cancelled or times out meanwhile.
+    fn checkout(&self, config: &GenerationConfig) -> Result<PooledContext<'_>> {
+        let mut context = self.contexts.checkout(|| config.interruption())?;
+        self.apply_lora(&mut context, config.lora.as_deref().unwrap_or(&self.lora))?;
+        Ok(context)
+    }
+
+    /// How long a request waits for a free context when all are busy;
+    /// `None` (the default) blocks until one is returned.
+    pub fn set_context_timeout(&mut self, timeout: Option<Duration>) {
+        self.contexts.set_timeout(timeout);
+    }
+
+    /// Persists cached prompt prefixes as llama session files in `dir`, so
+    /// they survive restarts.
+    pub fn set_prompt_cache_dir(&mut self, dir: impl Into<PathBuf>) {
+        self.prompt_cache.set_dir(dir);
+    }
+
+    /// Evaluates `prefix_tokens` once and keeps the resulting state, so later
+    /// prompts starting with the same tokens only evaluate their remainder.
+    pub fn cache_prefix(&self, prefix_tokens: &[i32]) -> Result<()> {
+        if prefix_tokens.is_empty() || self.prompt_cache.contains(prefix_tokens) {
+            return Ok(());
+        }
+
+        // Cached states are only valid under the configured adapters
+        let mut context = self.checkout(&GenerationConfig::default())?;
+        context.with_mut(|context| {
+            context.clear_kv_cache();
+            if self.prompt_cache.load_from_disk(context, prefix_tokens)? {
+                return Ok(());
+            }
+
+            Self::decode_tokens(context, prefix_tokens, 0, 0)?;
+            self.prompt_cache.store(context, prefix_tokens)
+        })
+    }
+
+    /// Feeds `input_tokens` into a freshly cleared KV cache, restoring the
+    /// longest cached prefix first when `use_cache` is set, and decodes the
+    /// rest in chunks of the context's batch size. The returned batch is
+    /// ready to be sampled at `n_tokens() - 1`.
+    fn eval_prompt(
         &self,
+        context: &mut LlamaContext<'_>,
         input_tokens: &[i32],
-        config: &GenerationConfig,
-    ) -> Result<Vec<i32>> {
-        let mut tokens = Vec::new();
-        let context = self.context.lock().unwrap();
-        
-        let sampler = LlamaSampler::chain_simple(vec![
-            LlamaSampler::temp(config.temperature),
+        use_cache: bool,
+    ) -> Result<LlamaBatch> {
+        if input_tokens.is_empty() {
+            return Err(anyhow::anyhow!("Cannot generate from an empty prompt"));
+        }
+        if input_tokens.len() >= context.n_ctx() as usize {
+            return Err(anyhow::anyhow!(
+                "Prompt length ({}) exceeds the context size ({})",
+                input_tokens.len(),
+                context.n_ctx()
+            ));
+        }
+
+        context.clear_kv_cache();
+        let cached = if use_cache { self.prompt_cache.longest_prefix(input_tokens) } else { None };
+        let start = match cached {
+            Some(prefix) => {
+                prefix.restore(context)?;
+                prefix.len()
+            }
+            None => 0,
+        };
+
+        Self::decode_tokens(context, &input_tokens[start..], start, 0)
+    }
+
+    /// Decodes `tokens` into sequence `seq_id` at positions starting from
+    /// `start`, requesting logits for the last one only.
+    fn decode_tokens(
+        context: &mut LlamaContext<'_>,
+        tokens: &[i32],
+        start: usize,
+        seq_id: i32,
+    ) -> Result<LlamaBatch> {
+        let n_batch = context.n_batch() as usize;
+        let mut batch = LlamaBatch::new(n_batch, 1);
+        let last_index = tokens.len() - 1;
+
+        for (chunk_index, chunk) in tokens.chunks(n_batch).enumerate() {
+            batch.clear();
+            for (offset, &token) in chunk.iter().enumerate() {
+                let index = chunk_index * n_batch + offset;
+                batch.add(LlamaToken(token), (start + index) as i32, &[seq_id], index == last_index)?;
+            }
+            context.decode(&mut batch)?;
+        }
+
+        Ok(batch)
+    }
+
+    /// Returns the logits for the token following `input_tokens`.
+    pub fn prompt_logits(&self, input_tokens: &[i32]) -> Result<Vec<f32>> {
+        let mut context = self.checkout(&GenerationConfig::default())?;
+        context.with_mut(|context| {
+            let batch = self.eval_prompt(context, input_tokens, true)?;
+            Ok(context.get_logits_ith(batch.n_tokens() - 1).to_vec())
+        })
+    }
+
+    /// Builds the chain in llama.cpp's usual order: penalties on the raw
+    /// logits, then the truncation samplers, then temperature, then the
+    /// final pick.
+    fn build_sampler(&self, config: &GenerationConfig) -> LlamaSampler {
+        LlamaSampler::chain_simple(vec![
             LlamaSampler::penalties(
                 self.model.n_vocab(),
                 self.model.token_eos().0,
                 self.model.token_nl().0,
-                0,  // penalty_last_n
+                config.penalty_last_n,
                 config.repetition_penalty,
-                0.0,  // penalty_freq
-                0.0,  // penalty_present
+                config.frequency_penalty,
+                config.presence_penalty,
                 false,  // penalize_nl
                 true,  // ignore_eos
-            )
-        ]);
+            ),
+            LlamaSampler::top_k(config.top_k),
+            LlamaSampler::typical(config.typical_p, 1),
+            LlamaSampler::top_p(config.top_p, 1),
+            LlamaSampler::min_p(config.min_p, 1),
+            LlamaSampler::temp(config.temperature),
+            // Only the last sampler of a chain selects the token; the ones
+            // above just reshape the distribution it draws from. Seeding it
+            // makes a fixed seed reproduce the same output.
+            LlamaSampler::dist(config.seed.unwrap_or(LLAMA_DEFAULT_SEED)),
+        ])
+    }
 
-        tokens.extend_from_slice(input_tokens);
+    /// The request's chain plus one for every class `config` overrides.
+    fn build_samplers(&self, config: &GenerationConfig) -> Samplers<'static> {
+        Samplers {
+            default: self.build_sampler(config),
+            by_class: SamplingClass::ALL.iter()
+                .filter_map(|&class| Some((class, self.build_sampler(&config.for_class(class)?))))
+                .collect(),
+            classifier: None,
+        }
+    }
 
-        while tokens.len() < config.max_length {
-            let token = sampler.sample(&context, tokens.len() as i32);
-            tokens.push(token.0);
-            
-            if self.model.is_eog_token(LlamaToken(token.0)) {
-                break;
+    /// Starts generation and returns an iterator that yields each new token
+    /// as soon as it has been sampled. The prompt is evaluated before this
+    /// returns; the stream holds one pooled context until it is dropped.
+    pub fn generate_stream(
+        &self,
+        input_tokens: &[i32],
+        config: &GenerationConfig,
+    ) -> Result<TokenStream<'_>> {
+        config.validate()?;
+        let mut context = self.checkout(config)?;
+        let use_cache = self.uses_default_lora(config);
+        let started = Instant::now();
+        let (max_length, batch) = context.with_mut(|context| -> Result<_> {
+            let max_length = config.max_length.min(context.n_ctx() as usize);
+            Ok((max_length, self.eval_prompt(context, input_tokens, use_cache)?))
+        })?;
+        let prompt_eval_time = started.elapsed();
+
+        Ok(TokenStream {
+            model: &self.model,
+            context,
+            samplers: self.build_samplers(config),
+            batch,
+            pending: None,
+            n_past: input_tokens.len(),
+            max_length,
+            cancelled: config.cancel.flag(),
+            deadline: config.deadline,
+            stop_reason: None,
+            failed: false,
+            filter: None,
+            stop_rule: None,
+            prompt_eval_time,
+            last_logprob: None,
+            logprobs: config.needs_logprobs(),
+            top_k_alternatives: config.trace_top_k.unwrap_or(0),
+            last_alternatives: Vec::new(),
+        })
+    }
+
+    /// Blocking counterpart of [`GGUFModel::generate_stream`]. Returns the
+    /// prompt followed by every generated token.
+    pub fn generate(
+        &self,
+        input_tokens: &[i32],
+        config: &GenerationConfig,
+    ) -> Result<Vec<i32>> {
+        let mut tokens = input_tokens.to_vec();
+        for token in self.generate_stream(input_tokens, config)? {
+            tokens.push(token?.0);
+        }
+        Ok(tokens)
+    }
+
+    /// Generates every sequence, decoding up to `n_seq_max` of them together
+    /// in one batch per step on a single pooled context. Each sequence has
+    /// its own sequence id, sampler, filter and stop rule; outputs are in
+    /// input order. A group's prompts and generations share the context's
+    /// KV cache, so each sequence is limited to an equal share of `n_ctx`
+    /// (prompt included); the prompt cache is not used.
+    pub fn generate_batch(&self, sequences: Vec<BatchSequence<'_>>) -> Result<Vec<BatchOutput>> {
+        let mut outputs = Vec::with_capacity(sequences.len());
+        let mut sequences = sequences.into_iter().peekable();
+        while sequences.peek().is_some() {
+            let group: Vec<_> = sequences.by_ref().take(self.n_seq_max).collect();
+            outputs.extend(self.generate_group(group)?);
+        }
+        Ok(outputs)
+    }
+
+    fn generate_group(&self, sequences: Vec<BatchSequence<'_>>) -> Result<Vec<BatchOutput>> {
+        // Adapters apply to the whole context, so the group has to agree on them
+        let config = &sequences[0].config;
+        if sequences.iter().any(|s| s.config.lora != config.lora) {
+            return Err(anyhow::anyhow!("All sequences of a batch must use the same LoRA adapters"));
+        }
+        for sequence in &sequences {
+            sequence.config.validate()?;
+        }
+        let mut context = self.checkout(config)?;
+        context.with_mut(|context| {
+            // The group shares one KV cache, so each sequence gets an equal
+            // share of it; otherwise decoding fails once it fills up
+            let n_ctx = context.n_ctx() as usize;
+            let budget = n_ctx / sequences.len();
+            if let Some((i, sequence)) = sequences.iter().enumerate().find(|(_, s)| s.input_tokens.len() >= budget) {
+                return Err(anyhow::anyhow!(
+                    "Prompt length ({}) of sequence {} exceeds its share ({}) of the context size ({}) among {} sequences",
+                    sequence.input_tokens.len(),
+                    i,
+                    budget,
+                    n_ctx,
+                    sequences.len()
+                ));
             }
+            context.clear_kv_cache();
+
+            // Prompts are evaluated one at a time so that each one's logits
+            // are still there to sample its first token from
+            let mut states = Vec::with_capacity(sequences.len());
+            for (seq_id, sequence) in sequences.into_iter().enumerate() {
+                if sequence.input_tokens.is_empty() {
+                    return Err(anyhow::anyhow!("Cannot generate from an empty prompt"));
+                }
+                let started = Instant::now();
+                let batch = Self::decode_tokens(context, &sequence.input_tokens, 0, seq_id as i32)?;
+                let mut state = BatchState {
+                    samplers: Samplers {
+                        classifier: sequence.classifier,
+                        ..self.build_samplers(&sequence.config)
+                    },
+                    n_past: sequence.input_tokens.len(),
+                    max_length: sequence.config.max_length.min(budget),
+                    pending: None,
+                    started: Instant::now(),
+                    config: sequence.config,
+                    filter: sequence.filter,
+                    stop_rule: sequence.stop_rule,
+                    output: BatchOutput {
+                        prompt_eval_time: started.elapsed(),
+                        ..Default::default()
+                    },
+                };
+                state.step(&self.model, context, batch.n_tokens() - 1)?;
+                states.push(state);
+            }
+
+            let mut batch = LlamaBatch::new(states.len(), 1);
+            loop {
+                batch.clear();
+                let mut running = Vec::with_capacity(states.len());
+                for (seq_id, state) in states.iter_mut().enumerate() {
+                    if let Some(token) = state.pending.take() {
+                        batch.add(token, state.n_past as i32 - 1, &[seq_id as i32], true)?;
+                        running.push(seq_id);
+                    }
+                }
+                if running.is_empty() {
+                    break;
+                }
+
+                context.decode(&mut batch)?;
+                for (idx, &seq_id) in running.iter().enumerate() {
+                    states[seq_id].step(&self.model, context, idx as i32)?;
+                }
+            }
+
+            Ok(states.into_iter().map(|state| state.output).collect())
+        })
+    }
+}
+
+impl LanguageModel for GGUFModel {
+    fn load(config: &GGUFModelConfig) -> Result<Self> {
+        let mut model = GGUFModel::new(
+            &config.model_path,
+            config.n_gpu_layers,
+            config.max_seq_length,
+            config.n_contexts,
+            &config.tuning,
+            &config.lora_adapters,
+        )?;
+        model.set_context_timeout(config.context_timeout);
+        if let Some(dir) = &config.speaker_cache_dir {
+            model.set_prompt_cache_dir(dir);
         }
+        Ok(model)
+    }
 
-        Ok(tokens)
+    fn generate_stream<'a>(
+        &'a self,
+        input_tokens: &[i32],
+        config: &GenerationConfig,
+    ) -> Result<Box<dyn TokenSource<'a> + 'a>> {
+        Ok(Box::new(GGUFModel::generate_stream(self, input_tokens, config)?))
+    }
+
+    fn generate_batch<'a>(&'a self, sequences: Vec<BatchSequence<'a>>) -> Result<Vec<BatchOutput>> {
+        GGUFModel::generate_batch(self, sequences)
+    }
+
+    fn cache_prefix(&self, prefix_tokens: &[i32]) -> Result<()> {
+        GGUFModel::cache_prefix(self, prefix_tokens)
+    }
+
+    fn metadata(&self) -> Option<&ModelMetadata> {
+        Some(&self.metadata)
+    }
+
+    fn token_piece(&self, token: i32) -> Option<String> {
+        self.model.token_to_str(LlamaToken(token), Special::Tokenize).ok()
+    }
+
+    fn tokenizer(&self) -> Option<Box<dyn PromptTokenizer>> {
+        Some(Box::new(GgufTokenizer::new(self.model.clone())))
     }
-}
\ No newline at end of file
+
+    fn context_size(&self) -> Option<usize> {
+        Some(self.n_ctx)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum StopReason {
+    /// The model sampled an end-of-generation token.
+    EndOfGeneration,
+    /// The sequence reached `max_length` or the context size.
+    MaxLength,
+    /// The stream's cancel flag was set.
+    Cancelled,
+    /// The request's deadline passed.
+    TimedOut,
+    /// The model closed the audio section (`<|audio_end|>`).
+    AudioEnd,
+    /// Every input word has been spoken.
+    AllWordsSpoken,
+    /// The model got stuck repeating itself, see [`crate::loop_detect`].
+    Loop,
+}
+
+impl StopReason {
+    /// Whether the request was cut short by its cancel token or deadline
+    /// rather than finishing.
+    pub fn is_interruption(self) -> bool {
+        matches!(self, StopReason::Cancelled | StopReason::TimedOut)
+    }
+}
+
+/// One sequence of a [`GGUFModel::generate_batch`] call.
+pub struct BatchSequence<'a> {
+    pub input_tokens: Vec<i32>,
+    pub config: GenerationConfig,
+    pub filter: Option<Box<dyn TokenFilter + 'a>>,
+    pub stop_rule: Option<Box<dyn StopRule + 'a>>,
+    pub classifier: Option<Box<dyn SamplingClassifier + 'a>>,
+}
+
+impl<'a> BatchSequence<'a> {
+    pub fn new(input_tokens: Vec<i32>, config: GenerationConfig) -> Self {
+        BatchSequence { input_tokens, config, filter: None, stop_rule: None, classifier: None }
+    }
+
+    /// Masks every token `filter` rejects before sampling.
+    pub fn with_filter(mut self, filter: Box<dyn TokenFilter + 'a>) -> Self {
+        self.filter = Some(filter);
+        self
+    }
+
+    /// Ends the sequence when `stop_rule` says so.
+    pub fn with_stop_rule(mut self, stop_rule: Box<dyn StopRule + 'a>) -> Self {
+        self.stop_rule = Some(stop_rule);
+        self
+    }
+
+    /// Picks the class settings each token is sampled with.
+    pub fn with_classifier(mut self, classifier: Box<dyn SamplingClassifier + 'a>) -> Self {
+        self.classifier = Some(classifier);
+        self
+    }
+}
+
+/// What one sequence of a batch generated.
+#[derive(Debug, Clone, Default)]
+pub struct BatchOutput {
+    /// Generated tokens, without the prompt
+    pub tokens: Vec<i32>,
+    /// Raw log-probability of each generated token, when the sequence's
+    /// config [needs them](GenerationConfig::needs_logprobs)
+    pub logprobs: Vec<f32>,
+    pub stop_reason: Option<StopReason>,
+    pub prompt_eval_time: Duration,
+    /// Time from the end of this sequence's prompt evaluation until it stopped
+    pub generation_time: Duration,
+}
+
+/// A running sequence of a batch: [`TokenStream`]'s state without the
+/// context, which the whole group shares.
+struct BatchState<'a> {
+    config: GenerationConfig,
+    samplers: Samplers<'a>,
+    filter: Option<Box<dyn TokenFilter + 'a>>,
+    stop_rule: Option<Box<dyn StopRule + 'a>>,
+    /// Sampled token waiting to be decoded in the next batch
+    pending: Option<LlamaToken>,
+    n_past: usize,
+    max_length: usize,
+    /// When the prompt was evaluated and generation began
+    started: Instant,
+    output: BatchOutput,
+}
+
+impl BatchState<'_> {
+    /// Samples the next token from batch index `idx` unless the sequence is
+    /// over, leaving it pending if the sequence goes on.
+    fn step(&mut self, model: &LlamaModel, context: &LlamaContext<'_>, idx: i32) -> Result<()> {
+        if let Some(reason) = self.config.interruption() {
+            self.stop(reason);
+            return Ok(());
+        }
+        if self.n_past >= self.max_length {
+            self.stop(StopReason::MaxLength);
+            return Ok(());
+        }
+
+        let logprobs = self.config.needs_logprobs();
+        let Sampled { token, logprob, .. } =
+            self.samplers.sample(context, idx, self.filter.as_deref_mut(), logprobs, 0)?;
+        self.n_past += 1;
+        self.output.tokens.push(token.0);
+        self.output.logprobs.extend(logprob);
+
+        if model.is_eog_token(token) {
+            self.stop(StopReason::EndOfGeneration);
+        } else if let Some(reason) = self.stop_rule.as_mut().and_then(|rule| rule.check(token)) {
+            self.stop(reason);
+        } else {
+            self.pending = Some(token);
+        }
+        Ok(())
+    }
+
+    fn stop(&mut self, reason: StopReason) {
+        self.output.stop_reason = Some(reason);
+        self.output.generation_time = self.started.elapsed();
+    }
+}
+
+pub struct TokenStream<'a> {
+    model: &'a LlamaModel,
+    context: PooledContext<'a>,
+    samplers: Samplers<'a>,
+    batch: LlamaBatch,
+    /// Last yielded token, decoded lazily on the next call so that callers
+    /// receive it before the forward pass runs.
+    pending: Option<LlamaToken>,
+    n_past: usize,
+    max_length: usize,
+    cancelled: Arc<AtomicBool>,
+    deadline: Option<Instant>,
+    stop_reason: Option<StopReason>,
+    failed: bool,
+    filter: Option<Box<dyn TokenFilter + 'a>>,
+    stop_rule: Option<Box<dyn StopRule + 'a>>,
+    prompt_eval_time: Duration,
+    last_logprob: Option<f32>,
+    /// Whether to compute `last_logprob` at all
+    logprobs: bool,
+    /// Alternatives to record per token; 0 skips computing them
+    top_k_alternatives: usize,
+    last_alternatives: Vec<(LlamaToken, f32)>,
+}
+
+/// Restricts which tokens may be sampled at each step of a [`TokenStream`].
+pub trait TokenFilter: Send {
+    /// Whether `token` may be sampled at the current step.
+    fn allows(&self, token: LlamaToken) -> bool;
+
+    /// Advances past a token that was just sampled.
+    fn accept(&mut self, token: LlamaToken);
+}
+
+/// Decides which [`SamplingClass`]'s settings the next token of a
+/// [`TokenStream`] is sampled with.
+pub trait SamplingClassifier: Send {
+    /// Class of the next token when it follows from the tokens so far
+    /// alone, which spares finding the most likely candidate.
+    fn fixed_class(&self) -> Option<SamplingClass> {
+        None
+    }
+
+    /// Class of the next token; `top` is the most likely candidate that
+    /// the filter allows.
+    fn next_class(&self, top: LlamaToken) -> SamplingClass;
+
+    /// Advances past a token that was just sampled.
+    fn accept(&mut self, token: LlamaToken);
+}
+
+/// Ends a [`TokenStream`] early based on the tokens sampled so far.
+pub trait StopRule: Send {
+    /// Called with each sampled token; returning a reason ends the stream
+    /// after that token has been yielded.
+    fn check(&mut self, token: LlamaToken) -> Option<StopReason>;
+}
+
+/// Stops as soon as any of its rules does. Every rule sees every token, and
+/// the first rule's reason wins when several fire together.
+pub struct AnyStop<'a>(pub Vec<Box<dyn StopRule + 'a>>);
+
+impl StopRule for AnyStop<'_> {
+    fn check(&mut self, token: LlamaToken) -> Option<StopReason> {
+        self.0.iter_mut().fold(None, |reason, rule| {
+            let fired = rule.check(token);
+            reason.or(fired)
+        })
+    }
+}
+
+impl<'a> TokenStream<'a> {
+    /// Masks every token `filter` rejects before sampling.
+    pub fn with_filter(mut self, filter: Box<dyn TokenFilter + 'a>) -> Self {
+        self.filter = Some(filter);
+        self
+    }
+
+    /// Ends the stream when `stop_rule` says so, in addition to EOG and
+    /// `max_length`.
+    pub fn with_stop_rule(mut self, stop_rule: Box<dyn StopRule + 'a>) -> Self {
+        self.stop_rule = Some(stop_rule);
+        self
+    }
+
+    /// Samples each token with the settings of the class `classifier`
+    /// picks. Without one, `class_sampling` is ignored.
+    pub fn with_classifier(mut self, classifier: Box<dyn SamplingClassifier + 'a>) -> Self {
+        self.samplers.classifier = Some(classifier);
+        self
+    }
+
+    /// Flag that stops the stream before its next token when set. It can be
+    /// handed to another thread.
+    pub fn cancel_flag(&self) -> Arc<AtomicBool> {
+        self.cancelled.clone()
+    }
+
+    /// Time spent evaluating the prompt (excluding any cached prefix).
+    pub fn prompt_eval_time(&self) -> Duration {
+        self.prompt_eval_time
+    }
+
+    /// Why the stream ended, or `None` while it is still running or if it
+    /// ended with an error.
+    pub fn stop_reason(&self) -> Option<StopReason> {
+        self.stop_reason
+    }
+
+    fn decode_pending(&mut self) -> Result<()> {
+        if let Some(token) = self.pending.take() {
+            self.batch.clear();
+            self.batch.add(token, self.n_past as i32 - 1, &[0], true)?;
+            let batch = &mut self.batch;
+            self.context.with_mut(|context| context.decode(batch))?;
+        }
+        Ok(())
+    }
+
+    /// Log-probability of the last yielded token under the model's raw
+    /// distribution, before any filter or sampler reshaped it. `None` unless
+    /// [`GenerationConfig::needs_logprobs`].
+    pub fn last_logprob(&self) -> Option<f32> {
+        self.last_logprob
+    }
+
+    /// The most likely tokens at the last step under the raw distribution,
+    /// with their log-probabilities, most likely first. Empty unless
+    /// `trace_top_k` was set.
+    pub fn last_alternatives(&self) -> &[(LlamaToken, f32)] {
+        &self.last_alternatives
+    }
+
+    fn sample(&mut self) -> Result<LlamaToken> {
+        let idx = self.batch.n_tokens() - 1;
+        let samplers = &mut self.samplers;
+        let filter = self.filter.as_deref_mut();
+        let (logprobs, top_k) = (self.logprobs, self.top_k_alternatives);
+        let sampled = self.context.with(|context| samplers.sample(context, idx, filter, logprobs, top_k))?;
+        self.last_logprob = sampled.logprob;
+        self.last_alternatives = sampled.alternatives;
+        Ok(sampled.token)
+    }
+}
+
+/// The request's sampler chain, plus one per [`SamplingClass`] with its own
+/// settings and the classifier that picks between them.
+struct Samplers<'a> {
+    default: LlamaSampler,
+    by_class: Vec<(SamplingClass, LlamaSampler)>,
+    classifier: Option<Box<dyn SamplingClassifier + 'a>>,
+}
+
+impl Samplers<'_> {
+    fn sample<F: TokenFilter + ?Sized>(
+        &mut self,
+        context: &LlamaContext<'_>,
+        idx: i32,
+        filter: Option<&mut F>,
+        logprob: bool,
+        top_k: usize,
+    ) -> Result<Sampled> {
+        // The classifier reads its top candidate off the same masked array
+        // the chosen chain then samples from
+        let mut candidates = None;
+        let class = match &self.classifier {
+            Some(classifier) if !self.by_class.is_empty() => classifier.fixed_class().or_else(|| {
+                let masked = candidates.insert(masked_candidates(context, idx, filter.as_deref()));
+                masked.data.iter()
+                    .filter(|candidate| candidate.logit() > f32::NEG_INFINITY)
+                    .max_by(|a, b| a.logit().total_cmp(&b.logit()))
+                    .map(|top| classifier.next_class(top.id()))
+            }),
+            _ => None,
+        };
+        let chosen = class.and_then(|class| self.by_class.iter().position(|(c, _)| *c == class));
+        let sampler = match chosen {
+            Some(i) => &mut self.by_class[i].1,
+            None => &mut self.default,
+        };
+        let sampled = sample_at(context, idx, sampler, filter, candidates, logprob, top_k)?;
+
+        // Every chain's penalties have to see every token
+        if chosen.is_some() {
+            self.default.accept(sampled.token);
+        }
+        for (i, (_, sampler)) in self.by_class.iter_mut().enumerate() {
+            if chosen != Some(i) {
+                sampler.accept(sampled.token);
+            }
+        }
+        if let Some(classifier) = self.classifier.as_mut() {
+            classifier.accept(sampled.token);
+        }
+        Ok(sampled)
+    }
+}
+
+/// The candidates following batch index `idx`, with every token `filter`
+/// rejects masked out.
+fn masked_candidates<F: TokenFilter + ?Sized>(
+    context: &LlamaContext<'_>,
+    idx: i32,
+    filter: Option<&F>,
+) -> LlamaTokenDataArray {
+    let mut candidates = context.token_data_array_ith(idx);
+    if let Some(filter) = filter {
+        for candidate in candidates.data.iter_mut() {
+            if !filter.allows(candidate.id()) {
+                candidate.set_logit(f32::NEG_INFINITY);
+            }
+        }
+    }
+    candidates
+}
+
+struct Sampled {
+    token: LlamaToken,
+    /// Raw log-probability of `token`
+    logprob: Option<f32>,
+    alternatives: Vec<(LlamaToken, f32)>,
+}
+
+/// Samples the token following batch index `idx`, masking whatever `filter`
+/// rejects, along with its raw log-probability if `logprob` is set and the
+/// `top_k` most likely raw alternatives. `candidates`, if given, are the
+/// already masked candidates at `idx`.
+fn sample_at<F: TokenFilter + ?Sized>(
+    context: &LlamaContext<'_>,
+    idx: i32,
+    sampler: &mut LlamaSampler,
+    filter: Option<&mut F>,
+    candidates: Option<LlamaTokenDataArray>,
+    logprob: bool,
+    top_k: usize,
+) -> Result<Sampled> {
+    let token = match candidates {
+        None if filter.is_none() => sampler.sample(context, idx),
+        candidates => {
+            let mut candidates = candidates
+                .unwrap_or_else(|| masked_candidates(context, idx, filter.as_deref()));
+            candidates.apply_sampler(sampler);
+            let token = candidates.selected_token()
+                .ok_or_else(|| anyhow::anyhow!("Sampler selected no token"))?;
+
+            sampler.accept(token);
+            if let Some(filter) = filter {
+                filter.accept(token);
+            }
+            token
+        }
+    };
+
+    let logits = context.get_logits_ith(idx);
+    Ok(Sampled {
+        token,
+        logprob: if logprob { log_softmax_at(logits, token) } else { None },
+        alternatives: if top_k == 0 { Vec::new() } else { top_logprobs(logits, top_k) },
+    })
+}
+
+/// `log(softmax(logits)[token])`, computed stably.
+fn log_softmax_at(logits: &[f32], token: LlamaToken) -> Option<f32> {
+    let logit = *logits.get(token.0 as usize)?;
+    let max = logits.iter().copied().fold(f32::NEG_INFINITY, f32::max);
+    let sum: f32 = logits.iter().map(|&l| (l - max).exp()).sum();
+    Some(logit - max - sum.ln())
+}
+
+/// The `k` largest entries of `log_softmax(logits)`, largest first.
+fn top_logprobs(logits: &[f32], k: usize) -> Vec<(LlamaToken, f32)> {
+    let max = logits.iter().copied().fold(f32::NEG_INFINITY, f32::max);
+    let log_sum = max + logits.iter().map(|&l| (l - max).exp()).sum::<f32>().ln();
+
+    let mut ids: Vec<usize> = (0..logits.len()).collect();
+    let by_logit = |a: &usize, b: &usize| logits[*b].total_cmp(&logits[*a]);
+    let k = k.min(ids.len());
+    if k < ids.len() {
+        ids.select_nth_unstable_by(k, by_logit);
+        ids.truncate(k);
+    }
+    ids.sort_by(by_logit);
+    ids.into_iter()
+        .map(|id| (LlamaToken(id as i32), logits[id] - log_sum))
+        .collect()
+}
+
+impl<'a> TokenSource<'a> for TokenStream<'a> {
+    fn set_filter(&mut self, filter: Box<dyn TokenFilter + 'a>) {
+        self.filter = Some(filter);
+    }
+
+    fn set_stop_rule(&mut self, stop_rule: Box<dyn StopRule + 'a>) {
+        self.stop_rule = Some(stop_rule);
+    }
+
+    fn set_classifier(&mut self, classifier: Box<dyn SamplingClassifier + 'a>) {
+        self.samplers.classifier = Some(classifier);
+    }
+
+    fn stop_reason(&self) -> Option<StopReason> {
+        TokenStream::stop_reason(self)
+    }
+
+    fn prompt_eval_time(&self) -> Duration {
+        TokenStream::prompt_eval_time(self)
+    }
+
+    fn cancel_flag(&self) -> Arc<AtomicBool> {
+        TokenStream::cancel_flag(self)
+    }
+
+    fn last_logprob(&self) -> Option<f32> {
+        TokenStream::last_logprob(self)
+    }
+
+    fn last_alternatives(&self) -> &[(LlamaToken, f32)] {
+        TokenStream::last_alternatives(self)
+    }
+}
+
+impl Iterator for TokenStream<'_> {
+    type Item = Result<LlamaToken>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.stop_reason.is_some() || self.failed {
+            return None;
+        }
+        if self.cancelled.load(Ordering::Relaxed) {
+            self.stop_reason = Some(StopReason::Cancelled);
+            return None;
+        }
+        if self.deadline.is_some_and(|deadline| Instant::now() >= deadline) {
+            self.stop_reason = Some(StopReason::TimedOut);
+            return None;
+        }
+        if self.n_past >= self.max_length {
+            self.stop_reason = Some(StopReason::MaxLength);
+            return None;
+        }
+
+        if let Err(e) = self.decode_pending() {
+            self.failed = true;
+            return Some(Err(e));
+        }
+
+        let token = match self.sample() {
+            Ok(token) => token,
+            Err(e) => {
+                self.failed = true;
+                return Some(Err(e));
+            }
+        };
+        self.n_past += 1;
+
+        if self.model.is_eog_token(token) {
+            self.stop_reason = Some(StopReason::EndOfGeneration);
+        } else if let Some(reason) = self.stop_rule.as_mut().and_then(|rule| rule.check(token)) {
+            self.stop_reason = Some(reason);
+        } else {
+            self.pending = Some(token);
+        }
+
+        Some(Ok(token))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::prompt_processor::PromptProcessor;
+
+    fn encode(processor: &PromptProcessor, text: &str) -> Vec<i32> {
+        let prompt = processor.get_completion_prompt(text, "en", None);
+        processor.encode_prompt(&prompt).unwrap().iter().map(|&x| x as i32).collect()
+    }
+
+    /// The prompt has to reach the context: two different texts must not
+    /// produce the same next-token distribution.
+    #[test]
+    fn test_prompt_changes_logits() {
+        let model = GGUFModel::default().unwrap();
+        let processor = PromptProcessor::new().unwrap();
+
+        let first = model.prompt_logits(&encode(&processor, "hello there")).unwrap();
+        let second = model.prompt_logits(&encode(&processor, "the weather is cold today")).unwrap();
+        let repeated = model.prompt_logits(&encode(&processor, "hello there")).unwrap();
+
+        assert_eq!(first.len(), second.len());
+        assert_ne!(first, second);
+        assert_eq!(first, repeated);
+    }
+
+    #[test]
+    fn test_stream_matches_blocking_generate() {
+        let model = GGUFModel::default().unwrap();
+        let processor = PromptProcessor::new().unwrap();
+        let prompt = encode(&processor, "hello there");
+        let config = GenerationConfig { temperature: 0.0, max_length: prompt.len() + 64, ..Default::default() };
+
+        let mut stream = model.generate_stream(&prompt, &config).unwrap();
+        let streamed: Vec<i32> = stream.by_ref().map(|t| t.unwrap().0).collect();
+        assert!(stream.stop_reason().is_some());
+        drop(stream);
+
+        let blocking = model.generate(&prompt, &config).unwrap();
+        assert_eq!(&blocking[..prompt.len()], &prompt[..]);
+        assert_eq!(&blocking[prompt.len()..], &streamed[..]);
+    }
+
+    #[test]
+    fn test_same_seed_same_tokens() {
+        let model = GGUFModel::default().unwrap();
+        let processor = PromptProcessor::new().unwrap();
+        let prompt = encode(&processor, "hello there");
+        let config = GenerationConfig {
+            temperature: 0.8,
+            max_length: prompt.len() + 64,
+            seed: Some(1234),
+            ..Default::default()
+        };
+
+        let first = model.generate(&prompt, &config).unwrap();
+        let second = model.generate(&prompt, &config).unwrap();
+        assert_eq!(first, second);
+    }
+
+    #[test]
+    fn test_cached_prefix_matches_full_eval() {
+        let model = GGUFModel::default().unwrap();
+        let processor = PromptProcessor::new().unwrap();
+        let prompt = encode(&processor, "hello there");
+        let uncached = model.prompt_logits(&prompt).unwrap();
+
+        model.cache_prefix(&prompt[..prompt.len() / 2]).unwrap();
+        let cached = model.prompt_logits(&prompt).unwrap();
+
+        let max_diff = uncached.iter().zip(&cached)
+            .map(|(a, b)| (a - b).abs())
+            .fold(0.0f32, f32::max);
+        assert!(max_diff < 1e-3, "max logit difference {}", max_diff);
+    }
+
+    #[test]
+    fn test_parallel_contexts() {
+        let model = GGUFModel::new(DEFAULT_MODEL_PATH, 0, Some(1024), 2, &ModelTuning::default(), &[]).unwrap();
+        let processor = PromptProcessor::new().unwrap();
+        let prompt = encode(&processor, "hello there");
+        let config = GenerationConfig { max_length: prompt.len() + 32, seed: Some(1), ..Default::default() };
+
+        let outputs: Vec<Vec<i32>> = std::thread::scope(|scope| {
+            let handles: Vec<_> = (0..2)
+                .map(|_| scope.spawn(|| model.generate(&prompt, &config).unwrap()))
+                .collect();
+            handles.into_iter().map(|h| h.join().unwrap()).collect()
+        });
+        assert_eq!(outputs[0], outputs[1]);
+    }
+
+    #[test]
+    fn test_context_checkout_timeout() {
+        let mut model = GGUFModel::new(DEFAULT_MODEL_PATH, 0, Some(1024), 1, &ModelTuning::default(), &[]).unwrap();
+        model.set_context_timeout(Some(Duration::from_millis(50)));
+        let processor = PromptProcessor::new().unwrap();
+        let prompt = encode(&processor, "hello there");
+
+        let stream = model.generate_stream(&prompt, &GenerationConfig::default()).unwrap();
+        assert!(model.prompt_logits(&prompt).is_err());
+        drop(stream);
+        assert!(model.prompt_logits(&prompt).is_ok());
+
+        // A cancelled request stops waiting even when the pool never times out
+        model.set_context_timeout(None);
+        let stream = model.generate_stream(&prompt, &GenerationConfig::default()).unwrap();
+        let config = GenerationConfig::default();
+        config.cancel.cancel();
+        assert!(model.generate_stream(&prompt, &config).is_err());
+        drop(stream);
+    }
+
+    /// Models and their pooled contexts must tear down cleanly in any order
+    /// of use, and a model loaded after others were dropped must behave the
+    /// same as the first. This loads the GGUF eight times over FFI, which
+    /// Miri cannot run, so it is meant for AddressSanitizer:
+    ///
+    /// ```text
+    /// RUSTFLAGS=-Zsanitizer=address cargo +nightly test -Zbuild-std \
+    ///     --target x86_64-unknown-linux-gnu -- --ignored test_create_and_drop_many_models
+    /// ```
+    ///
+    /// `backend::tests::test_create_and_drop_many_backends` covers the
+    /// borrows between backends and streams under Miri.
+    #[test]
+    #[ignore = "loads the GGUF eight times; run under AddressSanitizer"]
+    fn test_create_and_drop_many_models() {
+        let processor = PromptProcessor::new().unwrap();
+        let prompt = encode(&processor, "hello");
+        let config = GenerationConfig { temperature: 0.0, ..Default::default() };
+
+        let mut first_tokens = Vec::new();
+        for i in 0..8 {
+            let model = GGUFModel::new(DEFAULT_MODEL_PATH, 0, Some(512), 1 + i % 3, &ModelTuning::default(), &[]).unwrap();
+            if i % 2 == 0 {
+                let mut stream = model.generate_stream(&prompt, &config).unwrap();
+                first_tokens.push(stream.next().unwrap().unwrap());
+            }
+            drop(model);
+        }
+
+        assert_eq!(first_tokens.len(), 4);
+        assert!(first_tokens.iter().all(|&token| token == first_tokens[0]));
+    }
+
+    #[test]
+    fn test_batch_sequences_are_independent() {
+        let tuning = ModelTuning { n_seq_max: 2, ..Default::default() };
+        let model = GGUFModel::new(DEFAULT_MODEL_PATH, 0, Some(2048), 1, &tuning, &[]).unwrap();
+        let processor = PromptProcessor::new().unwrap();
+        let prompt = encode(&processor, "hello there");
+        let other = encode(&processor, "the weather is cold today");
+        let config = GenerationConfig {
+            temperature: 0.0,
+            max_length: other.len() + 32,
+            // Log-probabilities without alternatives
+            trace_top_k: Some(0),
+            ..Default::default()
+        };
+
+        let sequences = [&prompt, &other, &prompt]
+            .iter()
+            .map(|tokens| BatchSequence::new(tokens.to_vec(), config.clone()))
+            .collect();
+        let outputs = model.generate_batch(sequences).unwrap();
+
+        assert_eq!(outputs.len(), 3);
+        assert!(outputs.iter().all(|o| !o.tokens.is_empty() && o.stop_reason.is_some()));
+        assert_eq!(outputs[0].tokens, outputs[2].tokens);
+        assert_eq!(outputs[0].tokens.len(), outputs[0].logprobs.len());
+    }
+
+    #[test]
+    fn test_tuning_validation() {
+        assert!(ModelTuning::default().validate().is_ok());
+        assert!(ModelTuning { n_threads: Some(0), ..Default::default() }.validate().is_err());
+        assert!(ModelTuning { n_ubatch: 4096, ..Default::default() }.validate().is_err());
+        assert!(ModelTuning { n_seq_max: 0, ..Default::default() }.validate().is_err());
+        assert!(ModelTuning { kv_cache_type: KvCacheType::Q8_0, ..Default::default() }.validate().is_err());
+        assert!(ModelTuning {
+            kv_cache_type: KvCacheType::Q8_0,
+            flash_attention: true,
+            ..Default::default()
+        }.validate().is_ok());
+        assert_eq!("Q8_0".parse::<KvCacheType>(), Ok(KvCacheType::Q8_0));
+    }
+
+    #[test]
+    fn test_class_overrides() {
+        let config = GenerationConfig {
+            class_sampling: ClassSampling {
+                time: SamplingOverride { temperature: Some(0.0), ..Default::default() },
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        let time = config.for_class(SamplingClass::Time).unwrap();
+        assert_eq!(time.temperature, 0.0);
+        assert_eq!(time.top_p, config.top_p);
+        assert!(config.for_class(SamplingClass::AudioCode).is_none());
+        assert!(GenerationConfig::default().class_sampling.is_empty());
+    }
+
+    #[test]
+    fn test_top_logprobs() {
+        let logits = [0.0, 2.0, 1.0, -1.0];
+        let top = top_logprobs(&logits, 2);
+        assert_eq!(top.iter().map(|(t, _)| t.0).collect::<Vec<_>>(), vec![1, 2]);
+        assert!((top[0].1 - log_softmax_at(&logits, LlamaToken(1)).unwrap()).abs() < 1e-6);
+        assert_eq!(top_logprobs(&logits, 10).len(), 4);
+    }
+
+    #[test]
+    fn test_parse_lora_adapter() {
+        let adapter: LoraAdapter = "voices/house.gguf:0.75".parse().unwrap();
+        assert_eq!(adapter, LoraAdapter { path: "voices/house.gguf".into(), scale: 0.75 });
+        let adapter: LoraAdapter = "voices/house.gguf".parse().unwrap();
+        assert_eq!(adapter.scale, 1.0);
+        // A colon that is not followed by a scale belongs to the path
+        let adapter: LoraAdapter = "C:/voices/house.gguf".parse().unwrap();
+        assert_eq!(adapter.path, PathBuf::from("C:/voices/house.gguf"));
+        assert!("".parse::<LoraAdapter>().is_err());
+    }
+
+    #[test]
+    fn test_resolved_seed() {
+        let config = GenerationConfig { seed: Some(7), ..Default::default() };
+        assert_eq!(config.with_resolved_seed().seed, Some(7));
+        assert!(GenerationConfig::default().with_resolved_seed().seed.is_some());
+        assert!(config.validate().is_ok());
+        assert!(GenerationConfig { seed: Some(LLAMA_DEFAULT_SEED), ..Default::default() }.validate().is_err());
+
+        // Retries of candidate 7 must not land on the seeds of candidates 8, 9, ...
+        let retries: Vec<u32> = (1..=3).filter_map(|retry| config.retry_seed(retry)).collect();
+        assert_eq!(retries.len(), 3);
+        assert!(retries.iter().all(|&seed| !(7..16).contains(&seed)));
+        assert!(retries[0] != retries[1] && retries[1] != retries[2]);
+        assert_eq!(config.retry_seed(1), config.retry_seed(1));
+        assert_eq!(GenerationConfig::default().retry_seed(1), None);
+
+        assert_eq!(config.candidate_seed(2), Some(9));
+        let last = GenerationConfig { seed: Some(LLAMA_DEFAULT_SEED - 1), ..Default::default() };
+        assert_eq!(last.candidate_seed(1), Some(0));
+        assert_eq!(GenerationConfig::default().candidate_seed(1), None);
+    }
+
+    #[test]
+    fn test_stream_cancel() {
+        let model = GGUFModel::default().unwrap();
+        let processor = PromptProcessor::new().unwrap();
+        let prompt = encode(&processor, "hello there");
+
+        let mut stream = model.generate_stream(&prompt, &GenerationConfig::default()).unwrap();
+        assert!(stream.next().is_some());
+        stream.cancel_flag().store(true, Ordering::Relaxed);
+        assert!(stream.next().is_none());
+        assert_eq!(stream.stop_reason(), Some(StopReason::Cancelled));
+    }
+}
@@ -0,0 +1,214 @@
+use anyhow::Result;
+use llama_cpp_2::context::LlamaContext;
+use llama_cpp_2::token::LlamaToken;
+use std::collections::VecDeque;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+
+/// Context state captured right after a prompt prefix was evaluated.
+pub struct CachedPrompt {
+    tokens: Vec<i32>,
+    state: Vec<u8>,
+}
+
+impl CachedPrompt {
+    fn capture(context: &LlamaContext<'_>, tokens: &[i32]) -> Self {
+        let mut state = vec![0u8; context.get_state_size()];
+        // SAFETY: `state` is sized with llama's own upper bound for the copy
+        let written = unsafe { context.copy_state_data(state.as_mut_ptr()) };
+        state.truncate(written);
+
+        CachedPrompt {
+            tokens: tokens.to_vec(),
+            state,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.tokens.len()
+    }
+
+    /// Replaces the context's KV cache with the cached one.
+    pub fn restore(&self, context: &mut LlamaContext<'_>) -> Result<()> {
+        // SAFETY: the buffer came from `copy_state_data` on a context of the same model
+        let read = unsafe { context.set_state_data(&self.state) };
+        if read != self.state.len() {
+            return Err(anyhow::anyhow!(
+                "Restored {} of {} bytes of cached prompt state",
+                read,
+                self.state.len()
+            ));
+        }
+        Ok(())
+    }
+}
+
+/// Prompt states kept in memory when no capacity is given; each one is a
+/// full copy of a context's KV cache.
+pub const DEFAULT_CAPACITY: usize = 8;
+
+/// Evaluated prompt prefixes (typically speaker reference blocks) kept in
+/// memory and optionally persisted to a directory as llama session files.
+/// Only the `capacity` most recently used prefixes stay in memory.
+pub struct PromptCache {
+    /// Least recently used first
+    entries: Mutex<VecDeque<Arc<CachedPrompt>>>,
+    capacity: usize,
+    dir: Option<PathBuf>,
+    /// Identifies the model the states belong to; part of every session file name
+    identity: String,
+}
+
+impl PromptCache {
+    /// `identity` must differ between models (and anything else that changes
+    /// the evaluated state), so one model never loads another's session files.
+    pub fn new(identity: impl Into<String>, dir: Option<PathBuf>) -> Self {
+        PromptCache {
+            entries: Mutex::new(VecDeque::new()),
+            capacity: DEFAULT_CAPACITY,
+            dir,
+            identity: identity.into(),
+        }
+    }
+
+    pub fn set_dir(&mut self, dir: impl Into<PathBuf>) {
+        self.dir = Some(dir.into());
+    }
+
+    pub fn contains(&self, tokens: &[i32]) -> bool {
+        self.entries.lock().unwrap().iter().any(|e| e.tokens == tokens)
+    }
+
+    /// Longest cached prefix of `tokens` that still leaves at least one
+    /// token to evaluate, since sampling needs fresh logits.
+    pub fn longest_prefix(&self, tokens: &[i32]) -> Option<Arc<CachedPrompt>> {
+        let mut entries = self.entries.lock().unwrap();
+        let index = entries.iter()
+            .enumerate()
+            .filter(|(_, e)| e.tokens.len() < tokens.len() && tokens.starts_with(&e.tokens))
+            .max_by_key(|(_, e)| e.tokens.len())
+            .map(|(index, _)| index)?;
+
+        let entry = entries.remove(index)?;
+        entries.push_back(entry.clone());
+        Some(entry)
+    }
+
+    /// Captures the state of a context that has just evaluated `tokens`,
+    /// writing it to the cache directory if one is configured. Does nothing
+    /// if another request cached the same tokens first.
+    pub fn store(&self, context: &LlamaContext<'_>, tokens: &[i32]) -> Result<()> {
+        if !self.insert_with(tokens, || CachedPrompt::capture(context, tokens)) {
+            return Ok(());
+        }
+
+        if let Some(path) = self.session_path(tokens) {
+            std::fs::create_dir_all(path.parent().unwrap())?;
+            let llama_tokens: Vec<LlamaToken> = tokens.iter().map(|&t| LlamaToken(t)).collect();
+            context.state_save_file(&path, &llama_tokens)?;
+        }
+        Ok(())
+    }
+
+    /// Loads the session file for `tokens` into `context` if one exists and
+    /// was saved for exactly these tokens. Returns whether it was used.
+    pub fn load_from_disk(&self, context: &mut LlamaContext<'_>, tokens: &[i32]) -> Result<bool> {
+        let Some(path) = self.session_path(tokens) else {
+            return Ok(false);
+        };
+        if !path.exists() {
+            return Ok(false);
+        }
+
+        let saved = context.state_load_file(&path, context.n_ctx() as usize)?;
+        if !saved.iter().map(|t| t.0).eq(tokens.iter().copied()) {
+            eprintln!("Ignoring stale prompt cache file {}", path.display());
+            context.clear_kv_cache();
+            return Ok(false);
+        }
+
+        self.insert_with(tokens, || CachedPrompt::capture(context, tokens));
+        Ok(true)
+    }
+
+    /// Adds the prompt built by `capture` unless `tokens` are already cached,
+    /// evicting the least recently used entry when full. The check and the
+    /// insert happen under one lock. Returns whether it was added.
+    fn insert_with(&self, tokens: &[i32], capture: impl FnOnce() -> CachedPrompt) -> bool {
+        let mut entries = self.entries.lock().unwrap();
+        if entries.iter().any(|e| e.tokens == tokens) {
+            return false;
+        }
+
+        while entries.len() >= self.capacity.max(1) {
+            entries.pop_front();
+        }
+        entries.push_back(Arc::new(capture()));
+        true
+    }
+
+    fn session_path(&self, tokens: &[i32]) -> Option<PathBuf> {
+        let dir = self.dir.as_ref()?;
+        Some(dir.join(format!("{:016x}.session", self.session_key(tokens))))
+    }
+
+    /// FNV-1a over the model identity and the tokens, which unlike
+    /// `DefaultHasher` stays the same across Rust releases.
+    fn session_key(&self, tokens: &[i32]) -> u64 {
+        const OFFSET: u64 = 0xcbf2_9ce4_8422_2325;
+        const PRIME: u64 = 0x0000_0100_0000_01b3;
+
+        let identity = self.identity.as_bytes().iter().copied().chain([0]);
+        let tokens = tokens.iter().flat_map(|t| t.to_le_bytes());
+        identity.chain(tokens).fold(OFFSET, |hash, byte| (hash ^ byte as u64).wrapping_mul(PRIME))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn prompt(tokens: &[i32]) -> CachedPrompt {
+        CachedPrompt { tokens: tokens.to_vec(), state: Vec::new() }
+    }
+
+    #[test]
+    fn test_insert_is_deduplicated_and_bounded() {
+        let mut cache = PromptCache::new("model", None);
+        cache.capacity = 2;
+
+        assert!(cache.insert_with(&[1, 2], || prompt(&[1, 2])));
+        assert!(!cache.insert_with(&[1, 2], || unreachable!("already cached")));
+        assert!(cache.insert_with(&[3, 4], || prompt(&[3, 4])));
+
+        // Using [1, 2] makes [3, 4] the one to evict
+        assert_eq!(cache.longest_prefix(&[1, 2, 5]).map(|p| p.len()), Some(2));
+        assert!(cache.insert_with(&[5, 6], || prompt(&[5, 6])));
+        assert!(cache.contains(&[1, 2]));
+        assert!(!cache.contains(&[3, 4]));
+        assert!(cache.contains(&[5, 6]));
+    }
+
+    #[test]
+    fn test_longest_prefix_leaves_a_token() {
+        let cache = PromptCache::new("model", None);
+        cache.insert_with(&[1], || prompt(&[1]));
+        cache.insert_with(&[1, 2], || prompt(&[1, 2]));
+
+        assert_eq!(cache.longest_prefix(&[1, 2, 3]).map(|p| p.len()), Some(2));
+        assert_eq!(cache.longest_prefix(&[1, 2]).map(|p| p.len()), Some(1));
+        assert!(cache.longest_prefix(&[2, 1]).is_none());
+    }
+
+    #[test]
+    fn test_session_path_is_stable_and_model_specific() {
+        let a = PromptCache::new("a.gguf", Some(PathBuf::from("cache")));
+        let b = PromptCache::new("b.gguf", Some(PathBuf::from("cache")));
+
+        assert_eq!(a.session_path(&[1, 2, 3]), a.session_path(&[1, 2, 3]));
+        assert_ne!(a.session_path(&[1, 2, 3]), a.session_path(&[1, 2, 4]));
+        assert_ne!(a.session_path(&[1, 2, 3]), b.session_path(&[1, 2, 3]));
+        // Just the identity's NUL terminator: FNV-1a of "\0"
+        assert_eq!(PromptCache::new("", None).session_key(&[]), 0xaf63_bd4c_8601_b7df);
+    }
+}
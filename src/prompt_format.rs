@@ -0,0 +1,299 @@
+use std::collections::HashMap;
+use serde::{Serialize, Deserialize};
+
+use crate::utils::normalize;
+
+/// OuteTTS checkpoint generation whose prompt layout to use.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum PromptVersion {
+    V0_1,
+    V0_2,
+    V0_3,
+    /// Llama-based checkpoints with the two-codebook DAC codec, handled by
+    /// `dac_prompt` and `dac_codec` rather than a [`PromptFormat`].
+    V1_0,
+}
+
+impl std::str::FromStr for PromptVersion {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim_start_matches('v') {
+            "0.1" => Ok(PromptVersion::V0_1),
+            "0.2" => Ok(PromptVersion::V0_2),
+            "0.3" => Ok(PromptVersion::V0_3),
+            "1.0" | "1" => Ok(PromptVersion::V1_0),
+            _ => Err(format!("Unknown prompt version '{}', expected 0.1, 0.2, 0.3 or 1.0", s)),
+        }
+    }
+}
+
+impl PromptVersion {
+    /// Guesses the version from a model name such as `OuteTTS-0.3-500M`.
+    pub fn detect(model_name: &str) -> Option<Self> {
+        let name = model_name.to_lowercase();
+        if !name.contains("outetts") {
+            return None;
+        }
+        ["0.1", "0.2", "0.3", "1.0"].iter()
+            .find(|v| name.contains(*v))
+            .and_then(|v| v.parse().ok())
+    }
+
+    /// Whether this version decodes audio with the DAC codec.
+    pub fn uses_dac(self) -> bool {
+        self == PromptVersion::V1_0
+    }
+
+    pub fn format(self) -> anyhow::Result<Box<dyn PromptFormat>> {
+        match self {
+            PromptVersion::V0_1 | PromptVersion::V0_2 => Ok(Box::new(InterleavedFormat { version: self })),
+            PromptVersion::V0_3 => Ok(Box::new(PunctuatedFormat)),
+            PromptVersion::V1_0 => Err(anyhow::anyhow!("OuteTTS 1.0 uses the DAC prompt processor")),
+        }
+    }
+}
+
+/// Token ids the output parsers need.
+pub struct AudioTokenIds<'a> {
+    /// Token id to audio code
+    pub codes: &'a HashMap<i64, i64>,
+    pub code_start: i64,
+    pub code_end: i64,
+}
+
+/// Everything that differs between OuteTTS prompt versions.
+pub trait PromptFormat: Send + Sync {
+    fn version(&self) -> PromptVersion;
+
+    fn languages(&self) -> &'static [&'static str];
+
+    /// Template with `{bos}`, `{text_start}`, `{words}`, `{text_end}` and
+    /// `{audio_start}` placeholders.
+    fn text_prompt(&self) -> &'static str {
+        "{bos}\n{text_start}{words}{text_end}\n{audio_start}\n"
+    }
+
+    /// Named special token templates, e.g. `("time", "<|t_{:.2f}|>")`.
+    fn special_tokens(&self) -> &'static [(&'static str, &'static str)] {
+        &[
+            ("audio_code", "<|{}|>"),
+            ("text_start", "<|text_start|>"),
+            ("text_end", "<|text_end|>"),
+            ("audio_start", "<|audio_start|>"),
+            ("audio_end", "<|audio_end|>"),
+            ("time", "<|t_{:.2f}|>"),
+            ("code_start", "<|code_start|>"),
+            ("code_end", "<|code_end|>"),
+            ("text_sep", "<|text_sep|>"),
+        ]
+    }
+
+    /// Number of `<|n|>` audio code tokens in the vocabulary.
+    fn audio_code_count(&self) -> usize {
+        4100
+    }
+
+    /// Extra tokens that may follow a word's text in its audio block.
+    fn punctuation_tokens(&self) -> Vec<&'static str> {
+        Vec::new()
+    }
+
+    /// Normalises `text` into the words the prompt is built from.
+    fn sanitize_words(&self, text: &str) -> Vec<String>;
+
+    /// The text section between `{text_start}` and `{text_end}`.
+    fn join_words(&self, words: &[String]) -> String;
+
+    /// How `word` is written at the start of its audio block.
+    fn audio_word(&self, word: &str) -> String {
+        word.to_string()
+    }
+
+    /// Extracts the audio codes from generated tokens.
+    fn parse_output(&self, ids: &AudioTokenIds, tokens: &[i64]) -> Vec<i64>;
+}
+
+/// OuteTTS 0.1 and 0.2: bare lowercase words separated by `<|text_sep|>`.
+struct InterleavedFormat {
+    version: PromptVersion,
+}
+
+impl PromptFormat for InterleavedFormat {
+    fn version(&self) -> PromptVersion {
+        self.version
+    }
+
+    fn languages(&self) -> &'static [&'static str] {
+        match self.version {
+            PromptVersion::V0_1 => &["en"],
+            _ => &["en", "ja", "ko", "zh"],
+        }
+    }
+
+    fn sanitize_words(&self, text: &str) -> Vec<String> {
+        normalize::words(text)
+    }
+
+    fn join_words(&self, words: &[String]) -> String {
+        words.iter()
+            .map(|word| word.trim().to_string())
+            .collect::<Vec<String>>()
+            .join("<|text_sep|>")
+    }
+
+    fn parse_output(&self, ids: &AudioTokenIds, tokens: &[i64]) -> Vec<i64> {
+        tokens.iter().filter_map(|t| ids.codes.get(t).copied()).collect()
+    }
+}
+
+/// Punctuation OuteTTS 0.3 keeps, and the token it is spoken as.
+const PUNCTUATION: [(char, &str); 6] = [
+    ('.', "<|period|>"),
+    (',', "<|comma|>"),
+    ('?', "<|question_mark|>"),
+    ('!', "<|exclamation_mark|>"),
+    (':', "<|colon|>"),
+    (';', "<|semicolon|>"),
+];
+
+/// OuteTTS 0.3: words keep trailing punctuation, written as punctuation
+/// tokens in the audio blocks, and the text section is plain spaced text.
+struct PunctuatedFormat;
+
+impl PromptFormat for PunctuatedFormat {
+    fn version(&self) -> PromptVersion {
+        PromptVersion::V0_3
+    }
+
+    fn languages(&self) -> &'static [&'static str] {
+        &["en", "ja", "ko", "zh"]
+    }
+
+    fn punctuation_tokens(&self) -> Vec<&'static str> {
+        PUNCTUATION.iter().map(|(_, token)| *token).collect()
+    }
+
+    fn sanitize_words(&self, text: &str) -> Vec<String> {
+        let text = normalize::expand(text);
+
+        // Anything but a-z and apostrophes separates words, as in the
+        // 0.1/0.2 formats, but stays with the word before it so that the
+        // spoken punctuation can be kept
+        let mut spaced = String::with_capacity(text.len());
+        let mut in_word = false;
+        let mut after_word = false;
+        for c in text.chars() {
+            if c.is_ascii_lowercase() || c == '\'' {
+                if after_word {
+                    spaced.push(' ');
+                }
+                in_word = true;
+                after_word = false;
+            } else if c.is_whitespace() {
+                in_word = false;
+                after_word = false;
+            } else {
+                after_word = in_word;
+            }
+            spaced.push(c);
+        }
+
+        spaced.split_whitespace()
+            .filter_map(|raw| {
+                let word: String = raw.chars().filter(|c| c.is_ascii_lowercase() || *c == '\'').collect();
+                let word = word.trim_matches('\'');
+                if word.is_empty() {
+                    return None;
+                }
+                let punctuation: String = raw.chars().rev()
+                    .take_while(|c| !c.is_alphanumeric())
+                    .filter(|c| PUNCTUATION.iter().any(|(p, _)| p == c))
+                    .collect::<Vec<char>>().into_iter().rev()
+                    .collect();
+                Some(word.to_string() + &punctuation)
+            })
+            .collect()
+    }
+
+    fn join_words(&self, words: &[String]) -> String {
+        words.join(" ")
+    }
+
+    fn audio_word(&self, word: &str) -> String {
+        word.chars()
+            .map(|c| match PUNCTUATION.iter().find(|(p, _)| *p == c) {
+                Some((_, token)) => token.to_string(),
+                None => c.to_string(),
+            })
+            .collect()
+    }
+
+    fn parse_output(&self, ids: &AudioTokenIds, tokens: &[i64]) -> Vec<i64> {
+        // Only codes inside a <|code_start|>…<|code_end|> block count
+        let mut inside = false;
+        let mut codes = Vec::new();
+        for token in tokens {
+            if *token == ids.code_start {
+                inside = true;
+            } else if *token == ids.code_end {
+                inside = false;
+            } else if let (true, Some(&code)) = (inside, ids.codes.get(token)) {
+                codes.push(code);
+            }
+        }
+        codes
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_detect_version() {
+        assert_eq!(PromptVersion::detect("OuteTTS-0.2-500M"), Some(PromptVersion::V0_2));
+        assert_eq!(PromptVersion::detect("OuteTTS 0.3 1B"), Some(PromptVersion::V0_3));
+        assert_eq!(PromptVersion::detect("OuteTTS-1.0-1B"), Some(PromptVersion::V1_0));
+        assert_eq!(PromptVersion::detect("Qwen2.5 0.5B"), None);
+        assert_eq!("0.1".parse::<PromptVersion>(), Ok(PromptVersion::V0_1));
+    }
+
+    #[test]
+    fn test_punctuated_words() {
+        let format = PromptVersion::V0_3.format().unwrap();
+        let cases: &[(&str, &[&str])] = &[
+            ("Hello, world! It's 2 o'clock.", &["hello,", "world!", "it's", "two", "o'clock."]),
+            ("A well-known fact \u{2014} true.", &["a", "well", "known", "fact", "true."]),
+            ("a,b;c", &["a,", "b;", "c"]),
+            ("path/to_file\\name", &["path", "to", "file", "name"]),
+            ("He said 'hi' (twice)!", &["he", "said", "hi", "twice!"]),
+            ("'Quoted,' she said", &["quoted,", "she", "said"]),
+        ];
+        for (text, expected) in cases {
+            assert_eq!(format.sanitize_words(text), *expected, "{:?}", text);
+        }
+
+        let words = format.sanitize_words("Hello, world!");
+        assert_eq!(format.join_words(&words), "hello, world!");
+        assert_eq!(format.audio_word("hello,"), "hello<|comma|>");
+    }
+
+    #[test]
+    fn test_interleaved_words() {
+        let format = PromptVersion::V0_2.format().unwrap();
+        let words = format.sanitize_words("Hello, world!  It's 2 o'clock.");
+        assert_eq!(words, vec!["hello", "world", "its", "two", "oclock"]);
+        assert_eq!(format.join_words(&words[..2]), "hello<|text_sep|>world");
+    }
+
+    #[test]
+    fn test_parse_output() {
+        let codes: HashMap<i64, i64> = [(100, 1), (101, 2)].into_iter().collect();
+        let ids = AudioTokenIds { codes: &codes, code_start: 10, code_end: 11 };
+        let tokens = [100, 10, 101, 100, 11, 101];
+
+        assert_eq!(PromptVersion::V0_2.format().unwrap().parse_output(&ids, &tokens), vec![1, 2, 1, 2]);
+        assert_eq!(PromptVersion::V0_3.format().unwrap().parse_output(&ids, &tokens), vec![2, 1]);
+    }
+}
@@ -1,14 +1,15 @@
 use std::collections::HashMap;
-use tokenizers::Tokenizer;
 use serde::{Serialize, Deserialize};
 use std::path::Path;
 use anyhow::Result;
 
-use crate::utils::number_to_words::number_to_words;
+use crate::prompt_format::{AudioTokenIds, PromptFormat, PromptVersion};
+use crate::tokenizer::{HfTokenizer, PromptTokenizer};
 use crate::types::Speaker;
 
 pub struct PromptProcessor {
-    pub tokenizer: Tokenizer,
+    pub tokenizer: Box<dyn PromptTokenizer>,
+    format: Box<dyn PromptFormat>,
     bos: String,
     eos: String,
     special_tokens: HashMap<String, String>,
@@ -30,43 +31,55 @@ impl PromptProcessor {
     }
 
     pub fn new() -> Result<Self> {
+        Self::with_version(PromptVersion::V0_2)
+    }
+
+    pub fn with_version(version: PromptVersion) -> Result<Self> {
         let tokenizer_path = Self::ensure_tokenizer_file()?;
-        let tokenizer = Tokenizer::from_file(&tokenizer_path).map_err(|e| anyhow::anyhow!("{}", e))?;
+        Self::with_tokenizer(version, Box::new(HfTokenizer::from_file(&tokenizer_path)?))
+    }
+
+    /// Builds prompts with `tokenizer` instead of `models/tokenizer.json`,
+    /// e.g. the GGUF model's own vocabulary.
+    pub fn with_tokenizer(version: PromptVersion, tokenizer: Box<dyn PromptTokenizer>) -> Result<Self> {
+        let format = version.format()?;
         
         let mut processor = PromptProcessor {
             tokenizer,
             bos: "<|im_start|>".to_string(),
             eos: "<|im_end|>".to_string(),
-            special_tokens: HashMap::new(),
-            text_prompt: "{bos}\n{text_start}{words}{text_end}\n{audio_start}\n".to_string(),
+            special_tokens: format.special_tokens().iter()
+                .map(|&(name, token)| (name.to_string(), token.to_string()))
+                .collect(),
+            text_prompt: format.text_prompt().to_string(),
             map_audio_tokens: HashMap::new(),
-            languages: ["en", "ja", "ko", "zh"].iter().map(|&s| s.to_string()).collect(),
+            languages: format.languages().iter().map(|&s| s.to_string()).collect(),
+            format,
         };
 
-        processor.special_tokens.insert("audio_code".to_string(), "<|{}|>".to_string());
-        processor.special_tokens.insert("text_start".to_string(), "<|text_start|>".to_string());
-        processor.special_tokens.insert("text_end".to_string(), "<|text_end|>".to_string());
-        processor.special_tokens.insert("audio_start".to_string(), "<|audio_start|>".to_string());
-        processor.special_tokens.insert("audio_end".to_string(), "<|audio_end|>".to_string());
-        processor.special_tokens.insert("time".to_string(), "<|t_{:.2f}|>".to_string());
-        processor.special_tokens.insert("code_start".to_string(), "<|code_start|>".to_string());
-        processor.special_tokens.insert("code_end".to_string(), "<|code_end|>".to_string());
-        processor.special_tokens.insert("text_sep".to_string(), "<|text_sep|>".to_string());
-
-        processor.map_audio_tokens = processor.get_audio_token_map();
+        processor.map_audio_tokens = processor.get_audio_token_map()?;
         Ok(processor)
     }
 
-    fn get_audio_token_map(&self) -> HashMap<i64, i64> {
+    pub fn version(&self) -> PromptVersion {
+        self.format.version()
+    }
+
+    pub fn format(&self) -> &dyn PromptFormat {
+        self.format.as_ref()
+    }
+
+    /// Maps every audio code token id to its code. A tokenizer missing any
+    /// of them does not belong to an OuteTTS model.
+    fn get_audio_token_map(&self) -> Result<HashMap<i64, i64>> {
         let mut map = HashMap::new();
-        for i in 0..4100 {
-            let token = self.tokenizer.encode(
-                self.special_tokens["audio_code"].replace("{}", &i.to_string()),
-                false
-            ).unwrap().get_ids()[0] as i64;
+        for i in 0..self.format.audio_code_count() {
+            let piece = self.special_tokens["audio_code"].replace("{}", &i.to_string());
+            let token = self.tokenizer.token_to_id(&piece)
+                .ok_or_else(|| anyhow::anyhow!("The tokenizer has no {} token", piece))?;
             map.insert(token, i as i64);
         }
-        map
+        Ok(map)
     }
 
     pub fn process_text(&self, text: &str, language: &str) -> Vec<String> {
@@ -77,22 +90,13 @@ impl PromptProcessor {
             panic!("Non-English languages are not supported yet.");
         }
 
-        // Note: You'll need to implement number_to_words separately
-        let text = text.to_lowercase();
-        let text = regex::Regex::new(r"\d+(\.\d+)?").unwrap()
-            .replace_all(&text, |caps: &regex::Captures| {
-                number_to_words(&caps[0], None).unwrap_or_default()
-            })
-            .replace(&regex::Regex::new(r"[-_/,\.\\]").unwrap().to_string(), " ")
-            .replace(&regex::Regex::new(r"[^a-z\s]").unwrap().to_string(), "");
-        
-        text.split(" ").map(String::from).collect()
+        self.format.sanitize_words(text)
     }
 
     pub fn create_audio_prompt(&self, words: &[Word]) -> String {
         words.iter()
             .map(|i| {
-                let word = &i.word;
+                let word = self.format.audio_word(&i.word);
                 let duration = self.special_tokens["time"]
                     .replace("{:.2f}", &format!("{:.2}", i.duration));
                 let tokens = i.codes.iter()
@@ -111,6 +115,15 @@ impl PromptProcessor {
             .join("\n")
     }
 
+    fn text_prompt(&self, words: &[String]) -> String {
+        self.text_prompt
+            .replace("{bos}", &self.bos)
+            .replace("{text_start}", &self.special_tokens["text_start"])
+            .replace("{words}", &self.format.join_words(words))
+            .replace("{text_end}", &self.special_tokens["text_end"])
+            .replace("{audio_start}", &self.special_tokens["audio_start"])
+    }
+
     pub fn get_completion_prompt(&self, text: &str, language: &str, speaker: Option<&Speaker>) -> String {
         let mut words = self.process_text(text, language);
         
@@ -122,17 +135,7 @@ impl PromptProcessor {
             words.append(&mut speaker_words);
         }
 
-        let words = words.iter()
-            .map(|word| word.trim().to_string())
-            .collect::<Vec<String>>()
-            .join(&self.special_tokens["text_sep"]);
-
-        let mut prompt = self.text_prompt
-            .replace("{bos}", &self.bos)
-            .replace("{text_start}", &self.special_tokens["text_start"])
-            .replace("{words}", &words)
-            .replace("{text_end}", &self.special_tokens["text_end"])
-            .replace("{audio_start}", &self.special_tokens["audio_start"]);
+        let mut prompt = self.text_prompt(&words);
 
         if let Some(spk) = speaker {
             prompt.push_str(&self.create_audio_prompt(&spk.words));
@@ -141,20 +144,75 @@ impl PromptProcessor {
         prompt
     }
 
+    /// The speaker reference as a complete, closed utterance. Unlike the
+    /// interleaved layout of `get_completion_prompt`, it does not depend on
+    /// the target text, so it can be prepended to
+    /// `get_completion_prompt(text, language, None)` as a reusable prefix.
+    /// The two layouts carry the same words and codes, but the model was
+    /// only trained on the interleaved one.
+    pub fn get_speaker_prompt(&self, speaker: &Speaker) -> String {
+        let words = self.process_text(&speaker.text, &speaker.language);
+        format!(
+            "{}{}\n{}\n{}\n",
+            self.text_prompt(&words),
+            self.create_audio_prompt(&speaker.words),
+            self.special_tokens["audio_end"],
+            self.eos
+        )
+    }
+
     pub fn extract_audio_from_tokens(&self, tokens: &[i64]) -> Vec<i64> {
-        let mut result = Vec::new();
-        for token in tokens {
-            if let Some(&x) = self.map_audio_tokens.get(token) {
-                result.push(x);
-            }
+        let (Ok(code_start), Ok(code_end)) = (self.special_token_id("code_start"), self.special_token_id("code_end")) else {
+            return Vec::new();
+        };
+        let ids = AudioTokenIds {
+            codes: &self.map_audio_tokens,
+            code_start,
+            code_end,
+        };
+        self.format.parse_output(&ids, tokens)
+    }
+
+    /// Maps a token id to its audio code, if it is one.
+    pub fn audio_code(&self, token: i64) -> Option<i64> {
+        self.map_audio_tokens.get(&token).copied()
+    }
+
+    /// Token id of a single-token special marker such as `code_end`.
+    pub fn special_token_id(&self, name: &str) -> Result<i64> {
+        let token = self.special_tokens.get(name)
+            .ok_or_else(|| anyhow::anyhow!("Unknown special token {}", name))?;
+        let ids = self.encode_prompt(token)?;
+        match ids.as_slice() {
+            [id] => Ok(*id),
+            _ => Err(anyhow::anyhow!("Special token {} is not a single token", token)),
         }
-        result
+    }
+
+    /// Every marker and audio code token this format emits or parses, with
+    /// its id in the prompt tokenizer, for checking a model's vocabulary.
+    pub fn vocab_tokens(&self) -> Result<Vec<(String, i64)>> {
+        let mut pieces = vec![self.bos.clone(), self.eos.clone()];
+        pieces.extend(self.special_tokens.values()
+            .filter(|token| !token.contains('{'))
+            .cloned());
+        pieces.extend(self.format.punctuation_tokens().iter().map(|&token| token.to_string()));
+        pieces.extend((0..self.format.audio_code_count())
+            .map(|i| self.special_tokens["audio_code"].replace("{}", &i.to_string())));
+
+        let mut tokens = pieces.into_iter()
+            .map(|piece| {
+                let id = self.tokenizer.token_to_id(&piece)
+                    .ok_or_else(|| anyhow::anyhow!("The tokenizer has no {} token", piece))?;
+                Ok((piece, id))
+            })
+            .collect::<Result<Vec<_>>>()?;
+        tokens.sort_by_key(|&(_, id)| id);
+        Ok(tokens)
     }
 
     pub fn encode_prompt(&self, prompt: &str) -> Result<Vec<i64>> {
-        let encoding = self.tokenizer.encode(prompt, false)
-            .map_err(|e| anyhow::anyhow!("{}", e))?;
-        Ok(encoding.get_ids().iter().map(|&id| id as i64).collect())
+        self.tokenizer.encode(prompt)
     }
 }
 
@@ -164,3 +222,63 @@ pub struct Word {
     pub duration: f64,
     pub codes: Vec<i32>,
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::default_speakers::DEFAULT_SPEAKERS;
+
+    /// `tokenizer.json` without one of the audio codes, like the vocabulary
+    /// of a model that is not OuteTTS.
+    struct MissingCode(HfTokenizer);
+
+    impl PromptTokenizer for MissingCode {
+        fn encode(&self, text: &str) -> Result<Vec<i64>> {
+            self.0.encode(text)
+        }
+
+        fn token_to_id(&self, piece: &str) -> Option<i64> {
+            (piece != "<|500|>").then(|| self.0.token_to_id(piece)).flatten()
+        }
+
+        fn vocab(&self) -> HashMap<String, i64> {
+            self.0.vocab()
+        }
+
+        fn decode(&self, id: i64) -> Option<String> {
+            self.0.decode(id)
+        }
+    }
+
+    /// The cacheable layout carries the same words and reference codes as the
+    /// interleaved one; only where the text and the reference sit differs.
+    #[test]
+    fn test_speaker_prompt_layouts() {
+        let processor = PromptProcessor::new().unwrap();
+        let speaker: Speaker = serde_json::from_value(DEFAULT_SPEAKERS["en"]["male_1"].clone()).unwrap();
+        let text = "the weather is cold today";
+
+        let interleaved = processor.get_completion_prompt(text, "en", Some(&speaker));
+        let cached = processor.get_speaker_prompt(&speaker) + &processor.get_completion_prompt(text, "en", None);
+
+        let codes = |prompt: &str| processor.extract_audio_from_tokens(&processor.encode_prompt(prompt).unwrap());
+        assert!(!codes(&interleaved).is_empty());
+        assert_eq!(codes(&interleaved), codes(&cached));
+
+        let text_words = processor.process_text(text, "en");
+        let speaker_words = processor.process_text(&speaker.text, &speaker.language);
+        let all_words = [text_words.clone(), speaker_words.clone()].concat();
+        assert!(interleaved.contains(&processor.format.join_words(&all_words)));
+        assert!(cached.contains(&processor.format.join_words(&speaker_words)));
+        assert!(cached.contains(&processor.format.join_words(&text_words)));
+    }
+
+    #[test]
+    fn test_missing_audio_code() {
+        let tokenizer = HfTokenizer::from_file("models/tokenizer.json").unwrap();
+        let error = PromptProcessor::with_tokenizer(PromptVersion::V0_2, Box::new(MissingCode(tokenizer)))
+            .err()
+            .unwrap();
+        assert!(error.to_string().contains("<|500|>"), "{}", error);
+    }
+}
@@ -0,0 +1,119 @@
+use llama_cpp_2::token::LlamaToken;
+use serde::Serialize;
+
+use crate::grammar::{TokenClass, TokenClasses};
+
+/// Weight of `word_count_match` in [`CandidateScore::total`].
+const WORD_COUNT_WEIGHT: f64 = 2.0;
+/// Weight of `duration_plausibility` in [`CandidateScore::total`].
+const DURATION_WEIGHT: f64 = 1.0;
+
+/// How one `best_of` candidate was rated. Higher is better everywhere.
+#[derive(Debug, Clone, Serialize)]
+pub struct CandidateScore {
+    pub seed: u32,
+    /// Mean log-probability of the generated tokens; 0 when the backend
+    /// reports none
+    pub mean_logprob: f64,
+    /// Completed word blocks
+    pub words_spoken: usize,
+    /// 1 when one block was generated per input word, down to 0 when the
+    /// count is off by as many words as there are
+    pub word_count_match: f64,
+    /// Fraction of word blocks whose time token is plausible for their word
+    pub duration_plausibility: f64,
+    /// `mean_logprob + 2 * word_count_match + duration_plausibility`
+    pub total: f64,
+}
+
+/// Rough time `word` takes to say, in seconds: 60 ms per character plus
+/// 100 ms. Also what [`crate::loop_detect::LoopDetector`] judges time
+/// tokens against.
+pub fn typical_duration(word: &str) -> f32 {
+    0.1 + 0.06 * word.chars().count() as f32
+}
+
+/// Whether `seconds` is a believable duration for `word`: within a factor
+/// of three of its typical duration.
+fn plausible(word: &str, seconds: f32) -> bool {
+    let typical = typical_duration(word);
+    seconds >= typical / 3.0 && seconds <= typical * 3.0
+}
+
+/// Scores generated `tokens` against the input `words`. `logprobs` holds
+/// one entry per token the backend reported a log-probability for.
+pub fn score(
+    classes: &TokenClasses,
+    words: &[String],
+    tokens: &[i64],
+    logprobs: &[f32],
+    seed: u32,
+) -> CandidateScore {
+    let mean_logprob = if logprobs.is_empty() {
+        0.0
+    } else {
+        logprobs.iter().map(|&l| l as f64).sum::<f64>() / logprobs.len() as f64
+    };
+
+    let mut words_spoken = 0;
+    let mut timed = 0;
+    let mut plausible_words = 0;
+    for &token in tokens {
+        let token = LlamaToken(token as i32);
+        match classes.get(token) {
+            TokenClass::CodeEnd => words_spoken += 1,
+            TokenClass::Time => {
+                timed += 1;
+                let seconds = classes.time(token).unwrap_or_default();
+                // Blocks past the input words can't be plausible
+                if words.get(words_spoken).is_some_and(|word| plausible(word, seconds)) {
+                    plausible_words += 1;
+                }
+            }
+            _ => {}
+        }
+    }
+
+    let expected = words.len().max(1) as f64;
+    let word_count_match = (1.0 - words_spoken.abs_diff(words.len()) as f64 / expected).max(0.0);
+    let duration_plausibility = if timed == 0 { 0.0 } else { plausible_words as f64 / timed as f64 };
+
+    CandidateScore {
+        seed,
+        mean_logprob,
+        words_spoken,
+        word_count_match,
+        duration_plausibility,
+        total: mean_logprob + WORD_COUNT_WEIGHT * word_count_match + DURATION_WEIGHT * duration_plausibility,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::prompt_processor::PromptProcessor;
+
+    #[test]
+    fn test_complete_candidate_scores_higher() {
+        let processor = PromptProcessor::new().unwrap();
+        let classes = TokenClasses::new(&processor).unwrap();
+        let words = vec!["hello".to_string(), "world".to_string()];
+        let encode = |text: &str| processor.encode_prompt(text).unwrap();
+
+        let complete = encode("hello<|t_0.40|><|code_start|><|1|><|2|><|code_end|>\nworld<|t_0.42|><|code_start|><|3|><|code_end|>\n<|audio_end|>");
+        let short = encode("hello<|t_0.40|><|code_start|><|1|><|2|><|code_end|>\n<|audio_end|>");
+        let slow = encode("hello<|t_4.00|><|code_start|><|1|><|2|><|code_end|>\nworld<|t_4.00|><|code_start|><|3|><|code_end|>\n<|audio_end|>");
+
+        let complete = score(&classes, &words, &complete, &[], 1);
+        let short = score(&classes, &words, &short, &[], 2);
+        let slow = score(&classes, &words, &slow, &[], 3);
+
+        assert_eq!(complete.words_spoken, 2);
+        assert_eq!(complete.word_count_match, 1.0);
+        assert_eq!(complete.duration_plausibility, 1.0);
+        assert_eq!(short.word_count_match, 0.5);
+        assert_eq!(slow.duration_plausibility, 0.0);
+        assert!(complete.total > short.total);
+        assert!(complete.total > slow.total);
+    }
+}
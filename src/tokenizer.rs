@@ -0,0 +1,145 @@
+use anyhow::Result;
+use llama_cpp_2::model::{AddBos, LlamaModel, Special};
+use llama_cpp_2::token::LlamaToken;
+use std::collections::HashMap;
+use std::path::Path;
+use std::sync::Arc;
+use tokenizers::Tokenizer;
+
+/// Text to token ids for prompt building. Special tokens such as
+/// `<|audio_end|>` in the text are encoded as themselves, and no BOS is
+/// added.
+pub trait PromptTokenizer: Send + Sync {
+    fn encode(&self, text: &str) -> Result<Vec<i64>>;
+
+    /// Id of the single token spelled exactly `piece`.
+    fn token_to_id(&self, piece: &str) -> Option<i64>;
+
+    /// Every token in the vocabulary by its text.
+    fn vocab(&self) -> HashMap<String, i64>;
+
+    /// Decoded text of one token, special tokens included, so byte-level
+    /// pieces read as the characters they stand for.
+    fn decode(&self, id: i64) -> Option<String>;
+}
+
+/// A HuggingFace `tokenizer.json` shipped next to the model.
+pub struct HfTokenizer {
+    tokenizer: Tokenizer,
+}
+
+impl HfTokenizer {
+    pub fn from_file(path: impl AsRef<Path>) -> Result<Self> {
+        let path = path.as_ref();
+        let tokenizer = Tokenizer::from_file(path)
+            .map_err(|e| anyhow::anyhow!("Failed to load {}: {}", path.display(), e))?;
+        Ok(HfTokenizer { tokenizer })
+    }
+}
+
+impl PromptTokenizer for HfTokenizer {
+    fn encode(&self, text: &str) -> Result<Vec<i64>> {
+        let encoding = self.tokenizer.encode(text, false)
+            .map_err(|e| anyhow::anyhow!("{}", e))?;
+        Ok(encoding.get_ids().iter().map(|&id| id as i64).collect())
+    }
+
+    fn token_to_id(&self, piece: &str) -> Option<i64> {
+        self.tokenizer.token_to_id(piece).map(|id| id as i64)
+    }
+
+    fn vocab(&self) -> HashMap<String, i64> {
+        self.tokenizer.get_vocab(true).into_iter()
+            .map(|(piece, id)| (piece, id as i64))
+            .collect()
+    }
+
+    fn decode(&self, id: i64) -> Option<String> {
+        self.tokenizer.decode(&[u32::try_from(id).ok()?], false).ok()
+    }
+}
+
+/// The vocabulary embedded in the GGUF file, so no `tokenizer.json` is
+/// needed. Token text is decoded, so byte-level pieces appear as the
+/// characters they stand for rather than `tokenizer.json`'s `Ġ` spelling.
+pub struct GgufTokenizer {
+    model: Arc<LlamaModel>,
+}
+
+impl GgufTokenizer {
+    pub fn new(model: Arc<LlamaModel>) -> Self {
+        GgufTokenizer { model }
+    }
+}
+
+impl PromptTokenizer for GgufTokenizer {
+    fn encode(&self, text: &str) -> Result<Vec<i64>> {
+        let tokens = self.model.str_to_token(text, AddBos::Never)?;
+        Ok(tokens.iter().map(|token| token.0 as i64).collect())
+    }
+
+    fn token_to_id(&self, piece: &str) -> Option<i64> {
+        match self.model.str_to_token(piece, AddBos::Never).ok()?.as_slice() {
+            [token] => {
+                let text = self.model.token_to_str(*token, Special::Tokenize).ok()?;
+                (text == piece).then_some(token.0 as i64)
+            }
+            _ => None,
+        }
+    }
+
+    fn vocab(&self) -> HashMap<String, i64> {
+        // Tokens that are not valid UTF-8 on their own are skipped
+        (0..self.model.n_vocab())
+            .filter_map(|id| {
+                let piece = self.model.token_to_str(LlamaToken(id), Special::Tokenize).ok()?;
+                Some((piece, id as i64))
+            })
+            .collect()
+    }
+
+    fn decode(&self, id: i64) -> Option<String> {
+        self.model.token_to_str(LlamaToken(i32::try_from(id).ok()?), Special::Tokenize).ok()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backend::LanguageModel;
+    use crate::default_speakers::DEFAULT_SPEAKERS;
+    use crate::model::GGUFModel;
+    use crate::prompt_format::PromptVersion;
+    use crate::prompt_processor::PromptProcessor;
+    use crate::types::Speaker;
+
+    /// The GGUF vocabulary must encode the bundled speakers exactly like
+    /// `tokenizer.json`.
+    #[test]
+    fn test_gguf_tokenizer_matches_tokenizer_json() {
+        let model = GGUFModel::default().unwrap();
+        let gguf = PromptProcessor::with_tokenizer(
+            PromptVersion::V0_2,
+            model.tokenizer().unwrap(),
+        ).unwrap();
+        let hf = PromptProcessor::new().unwrap();
+
+        assert_eq!(gguf.vocab_tokens().unwrap(), hf.vocab_tokens().unwrap());
+        for (language, speakers) in DEFAULT_SPEAKERS.iter() {
+            for (name, speaker) in speakers.iter() {
+                let speaker: Speaker = serde_json::from_value(speaker.clone()).unwrap();
+                let mut prompts = vec![speaker.text.clone(), hf.create_audio_prompt(&speaker.words)];
+                if language == "en" {
+                    prompts.push(hf.get_speaker_prompt(&speaker));
+                }
+                for prompt in prompts {
+                    assert_eq!(
+                        gguf.encode_prompt(&prompt).unwrap(),
+                        hf.encode_prompt(&prompt).unwrap(),
+                        "{}/{}: {:?}", language, name, prompt
+                    );
+                }
+            }
+        }
+    }
+}
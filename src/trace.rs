@@ -0,0 +1,195 @@
+use anyhow::Result;
+use llama_cpp_2::token::LlamaToken;
+use serde::Serialize;
+use std::io::Write;
+use std::path::Path;
+
+use crate::grammar::{TokenClass, TokenClasses};
+use crate::tokenizer::PromptTokenizer;
+
+/// A token the model could have sampled instead.
+#[derive(Debug, Clone, Serialize)]
+pub struct TokenAlternative {
+    pub id: i32,
+    pub piece: String,
+    pub logprob: f32,
+}
+
+/// One sampled token, as recorded when `GenerationConfig::trace_top_k` is set.
+#[derive(Debug, Clone, Serialize)]
+pub struct TokenTrace {
+    pub id: i32,
+    /// Decoded token text; empty if the tokenizer cannot decode it
+    pub piece: String,
+    /// Log-probability under the raw distribution; `None` when the backend
+    /// reports none
+    pub logprob: Option<f32>,
+    /// Most likely tokens at this step, most likely first
+    pub alternatives: Vec<TokenAlternative>,
+}
+
+impl TokenTrace {
+    pub fn new(
+        tokenizer: &dyn PromptTokenizer,
+        token: i32,
+        logprob: Option<f32>,
+        alternatives: &[(LlamaToken, f32)],
+    ) -> Self {
+        let piece = |id: i32| tokenizer.decode(id as i64).unwrap_or_default();
+        TokenTrace {
+            id: token,
+            piece: piece(token),
+            logprob,
+            alternatives: alternatives.iter()
+                .map(|&(id, logprob)| TokenAlternative {
+                    id: id.0,
+                    piece: piece(id.0),
+                    logprob,
+                })
+                .collect(),
+        }
+    }
+}
+
+/// How sure the model was of one word block. Confidences are geometric
+/// mean probabilities, from 0 to 1, of the tokens in that part; `None`
+/// when the part is missing or has no log-probabilities.
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct WordConfidence {
+    pub word: String,
+    /// Seconds spelled by the block's time token
+    pub time: Option<f32>,
+    pub codes: usize,
+    pub text_confidence: Option<f32>,
+    pub time_confidence: Option<f32>,
+    pub codes_confidence: Option<f32>,
+    /// Over every token of the block
+    pub confidence: Option<f32>,
+}
+
+/// `exp(mean(logprobs))`, or `None` for no log-probabilities.
+fn geometric_mean(logprobs: &[f32]) -> Option<f32> {
+    if logprobs.is_empty() {
+        return None;
+    }
+    Some((logprobs.iter().sum::<f32>() / logprobs.len() as f32).exp())
+}
+
+#[derive(Default)]
+struct Block {
+    word: String,
+    time: Option<f32>,
+    codes: usize,
+    text: Vec<f32>,
+    timing: Vec<f32>,
+    code_logprobs: Vec<f32>,
+}
+
+impl Block {
+    fn is_empty(&self) -> bool {
+        self.word.is_empty() && self.time.is_none() && self.codes == 0
+    }
+
+    fn finish(self) -> WordConfidence {
+        let all: Vec<f32> = [&self.text, &self.timing, &self.code_logprobs].into_iter().flatten().copied().collect();
+        WordConfidence {
+            word: self.word,
+            time: self.time,
+            codes: self.codes,
+            text_confidence: geometric_mean(&self.text),
+            time_confidence: geometric_mean(&self.timing),
+            codes_confidence: geometric_mean(&self.code_logprobs),
+            confidence: geometric_mean(&all),
+        }
+    }
+}
+
+/// Splits `trace` into word blocks (`word <|t_x.xx|> <|code_start|> codes
+/// <|code_end|>`) and rates each. A trailing unfinished block is included,
+/// since its codes are decoded too.
+pub fn word_confidence(classes: &TokenClasses, trace: &[TokenTrace]) -> Vec<WordConfidence> {
+    let mut words = Vec::new();
+    let mut block = Block::default();
+    for token in trace {
+        let id = LlamaToken(token.id);
+        let logprob = token.logprob.into_iter();
+        match classes.get(id) {
+            TokenClass::Text | TokenClass::Punctuation => {
+                block.word.push_str(&token.piece);
+                block.text.extend(logprob);
+            }
+            TokenClass::Time => {
+                block.time = classes.time(id);
+                block.timing.extend(logprob);
+            }
+            TokenClass::CodeStart => block.code_logprobs.extend(logprob),
+            TokenClass::AudioCode => {
+                block.codes += 1;
+                block.code_logprobs.extend(logprob);
+            }
+            TokenClass::CodeEnd => {
+                block.code_logprobs.extend(logprob);
+                words.push(std::mem::take(&mut block).finish());
+            }
+            _ => {}
+        }
+    }
+    if !block.is_empty() {
+        words.push(block.finish());
+    }
+    words
+}
+
+/// One line of a trace file.
+#[derive(Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum TraceRecord<'a> {
+    Token(&'a TokenTrace),
+    Word(&'a WordConfidence),
+}
+
+/// Writes every token, then every word block, as one JSON object per line.
+pub fn write_jsonl(path: impl AsRef<Path>, trace: &[TokenTrace], words: &[WordConfidence]) -> Result<()> {
+    let mut out = std::io::BufWriter::new(std::fs::File::create(path)?);
+    let records = trace.iter().map(TraceRecord::Token).chain(words.iter().map(TraceRecord::Word));
+    for record in records {
+        serde_json::to_writer(&mut out, &record)?;
+        writeln!(out)?;
+    }
+    out.flush()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::prompt_processor::PromptProcessor;
+
+    #[test]
+    fn test_word_confidence() {
+        let processor = PromptProcessor::new().unwrap();
+        let classes = TokenClasses::new(&processor).unwrap();
+        let tokens = processor
+            .encode_prompt("hello<|t_0.40|><|code_start|><|1|><|2|><|code_end|>\nworld<|t_0.42|><|code_start|><|3|>")
+            .unwrap();
+        // The time token of the first word is the only unsure one
+        let trace: Vec<TokenTrace> = tokens.iter()
+            .map(|&id| {
+                let logprob = if classes.time(LlamaToken(id as i32)) == Some(0.40) { 0.5f32.ln() } else { 0.0 };
+                TokenTrace::new(processor.tokenizer.as_ref(), id as i32, Some(logprob), &[])
+            })
+            .collect();
+
+        let words = word_confidence(&classes, &trace);
+        assert_eq!(words.len(), 2);
+        assert_eq!(words[0].word, "hello");
+        assert_eq!(words[0].time, Some(0.40));
+        assert_eq!(words[0].codes, 2);
+        assert!((words[0].time_confidence.unwrap() - 0.5).abs() < 1e-6);
+        assert_eq!(words[0].text_confidence, Some(1.0));
+        assert!(words[0].confidence.unwrap() < 1.0);
+        assert_eq!(words[1].word, "world");
+        assert_eq!(words[1].codes, 1);
+        assert_eq!(words[1].confidence, Some(1.0));
+    }
+}
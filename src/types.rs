@@ -3,6 +3,7 @@ use crate::prompt_processor::Word;
 
 #[derive(Deserialize)]
 pub struct Speaker {
+    #[serde(default)]
     pub name: String,
     pub language: String,
     pub text: String,
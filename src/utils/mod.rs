@@ -1 +1,2 @@
-pub mod number_to_words;
\ No newline at end of file
+pub mod number_to_words;
+pub mod normalize;
\ No newline at end of file
@@ -0,0 +1,209 @@
+/*!
+English text normalization: turns free text into the lowercase words the
+OuteTTS prompts are built from, the way upstream OuteTTS does (`it's` becomes
+`its`, `well-known` becomes `well known`), with numbers and common
+abbreviations spelled out and accents stripped from Latin letters.
+*/
+
+use lazy_static::lazy_static;
+use regex::{Captures, Regex};
+use unicode_normalization::char::is_combining_mark;
+use unicode_normalization::UnicodeNormalization;
+
+use crate::utils::number_to_words::number_to_words;
+
+/// Abbreviations spelled out before punctuation is dropped. Matched as whole
+/// words, ignoring case, and only with their periods. `St.` is handled by
+/// [`SAINT`], since it is just as often a street.
+const ABBREVIATIONS: [(&str, &str); 12] = [
+    ("dr.", "doctor"),
+    ("mr.", "mister"),
+    ("mrs.", "missus"),
+    ("ms.", "miss"),
+    ("jr.", "junior"),
+    ("sr.", "senior"),
+    ("prof.", "professor"),
+    ("vs.", "versus"),
+    ("etc.", "et cetera"),
+    ("e.g.", "for example"),
+    ("i.e.", "that is"),
+    ("approx.", "approximately"),
+];
+
+/// Symbols that are read out as words.
+const SYMBOLS: [(char, &str); 2] = [
+    ('&', "and"),
+    ('%', "percent"),
+];
+
+/// Latin letters with no ASCII decomposition, spelled out the usual way.
+const LETTERS: [(char, &str); 9] = [
+    ('\u{df}', "ss"),
+    ('\u{e6}', "ae"),
+    ('\u{153}', "oe"),
+    ('\u{f8}', "o"),
+    ('\u{111}', "d"),
+    ('\u{f0}', "d"),
+    ('\u{fe}', "th"),
+    ('\u{142}', "l"),
+    ('\u{131}', "i"),
+];
+
+const DIGITS: [&str; 10] = ["zero", "one", "two", "three", "four", "five", "six", "seven", "eight", "nine"];
+
+lazy_static! {
+    static ref ABBREVIATION: Regex = {
+        let alternatives: Vec<String> = ABBREVIATIONS.iter().map(|(short, _)| regex::escape(short)).collect();
+        Regex::new(&format!(r"\b(?:{})", alternatives.join("|"))).unwrap()
+    };
+    /// `St.` before a capitalised name, as in `St. Patrick`, but not `Main St.`
+    static ref SAINT: Regex = Regex::new(r"\b[Ss]t\.(\s+\p{Lu})").unwrap();
+    /// Integers with optional thousands separators and decimals, e.g. `700,000` or `3.5`
+    static ref NUMBER: Regex = Regex::new(r"\d+(?:,\d{3})*(?:\.\d+)?").unwrap();
+}
+
+/// Spells out a number matched by [`NUMBER`], without hyphens.
+fn spell_number(number: &str) -> String {
+    let (integer, decimals) = number.split_once('.').unwrap_or((number, ""));
+    let mut words = number_to_words(&integer.replace(',', ""), None).unwrap_or_default();
+    if !decimals.is_empty() {
+        words.push_str(" point");
+        for digit in decimals.chars().filter_map(|c| c.to_digit(10)) {
+            words.push(' ');
+            words.push_str(DIGITS[digit as usize]);
+        }
+    }
+    words.replace('-', " ")
+}
+
+/// Decomposes `text` and drops the combining marks on Latin letters, so
+/// `naïve` becomes `naive`. Marks on other scripts are kept.
+fn strip_accents(text: &str) -> String {
+    let mut stripped = String::with_capacity(text.len());
+    let mut after_latin = false;
+    for c in text.nfkd() {
+        if !is_combining_mark(c) {
+            after_latin = c.is_ascii_alphabetic();
+        } else if after_latin {
+            continue;
+        }
+        stripped.push(c);
+    }
+    stripped.nfc().collect()
+}
+
+/// Lowercases `text` and spells out abbreviations, symbols and numbers,
+/// leaving the remaining punctuation in place. Curly quotes become
+/// straight ones, accents are stripped from Latin letters and [`LETTERS`]
+/// are transliterated.
+pub fn expand(text: &str) -> String {
+    let text = strip_accents(text);
+    let text = SAINT.replace_all(&text, "saint$1");
+    let mut lowered = String::with_capacity(text.len());
+    for c in text.to_lowercase().chars() {
+        match c {
+            '\u{2018}' | '\u{2019}' => lowered.push('\''),
+            '\u{201c}' | '\u{201d}' => lowered.push('"'),
+            c => match LETTERS.iter().find(|(letter, _)| *letter == c) {
+                Some((_, spelled)) => lowered.push_str(spelled),
+                None => lowered.push(c),
+            },
+        }
+    }
+    let text = lowered;
+
+    let text = ABBREVIATION.replace_all(&text, |caps: &Captures| {
+        let (_, expansion) = ABBREVIATIONS.iter()
+            .find(|(short, _)| **short == caps[0])
+            .expect("the pattern only matches listed abbreviations");
+        format!(" {} ", expansion)
+    });
+
+    let mut spelled = String::with_capacity(text.len());
+    for c in text.chars() {
+        match SYMBOLS.iter().find(|(symbol, _)| *symbol == c) {
+            Some((_, word)) => {
+                spelled.push(' ');
+                spelled.push_str(word);
+                spelled.push(' ');
+            }
+            None => spelled.push(c),
+        }
+    }
+
+    NUMBER.replace_all(&spelled, |caps: &Captures| format!(" {} ", spell_number(&caps[0])))
+        .into_owned()
+}
+
+/// Normalizes `text` into bare lowercase words. Apostrophes inside a word
+/// are dropped, so contractions stay one word; any other punctuation,
+/// hyphens included, separates words. Accents are stripped, letters such
+/// as `ß` are transliterated, and any other letter outside a-z separates
+/// words too.
+pub fn words(text: &str) -> Vec<String> {
+    let text = expand(text);
+    let chars: Vec<char> = text.chars().collect();
+
+    let mut cleaned = String::with_capacity(text.len());
+    for (i, &c) in chars.iter().enumerate() {
+        if c.is_ascii_lowercase() {
+            cleaned.push(c);
+        } else if c == '\'' {
+            let joins_letters = i > 0
+                && chars[i - 1].is_ascii_lowercase()
+                && chars.get(i + 1).is_some_and(|next| next.is_ascii_lowercase());
+            if !joins_letters {
+                cleaned.push(' ');
+            }
+        } else {
+            cleaned.push(' ');
+        }
+    }
+
+    cleaned.split_whitespace().map(String::from).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_words() {
+        let cases: &[(&str, &[&str])] = &[
+            ("", &[]),
+            ("...", &[]),
+            ("Hello, world.", &["hello", "world"]),
+            ("  lots   of\tspace\n", &["lots", "of", "space"]),
+            ("It's fine, don't worry.", &["its", "fine", "dont", "worry"]),
+            ("I\u{2019}ve said \u{201c}yes\u{201d}", &["ive", "said", "yes"]),
+            ("He said 'hi' (twice)!", &["he", "said", "hi", "twice"]),
+            ("A well-known state-of-the-art model", &["a", "well", "known", "state", "of", "the", "art", "model"]),
+            ("path/to\\file_name", &["path", "to", "file", "name"]),
+            ("Dr. Smith met Mr. Jones", &["doctor", "smith", "met", "mister", "jones"]),
+            ("St. Patrick's day", &["saint", "patricks", "day"]),
+            ("Main St. is closed", &["main", "st", "is", "closed"]),
+            ("Fruit, e.g. apples", &["fruit", "for", "example", "apples"]),
+            ("Pears, i.e. fruit, etc.", &["pears", "that", "is", "fruit", "et", "cetera"]),
+            ("Room 42", &["room", "forty", "two"]),
+            ("700,000 meters", &["seven", "hundred", "thousand", "meters"]),
+            ("3.5 liters", &["three", "point", "five", "liters"]),
+            ("Tom & Jerry", &["tom", "and", "jerry"]),
+            ("50% off", &["fifty", "percent", "off"]),
+            ("na\u{ef}ve caf\u{e9}", &["naive", "cafe"]),
+            ("Cre\u{300}me bru\u{302}le\u{301}e", &["creme", "brulee"]),
+            ("Stra\u{df}e", &["strasse"]),
+            ("\u{c6}sop's encyclop\u{e6}dia", &["aesops", "encyclopaedia"]),
+            ("K\u{f8}benhavn \u{141}\u{f3}d\u{17a}", &["kobenhavn", "lodz"]),
+            ("Caf\u{e9}\u{3b1}bar", &["cafe", "bar"]),
+        ];
+
+        for (text, expected) in cases {
+            assert_eq!(words(text), *expected, "{:?}", text);
+        }
+    }
+
+    #[test]
+    fn test_expand_keeps_punctuation() {
+        assert_eq!(expand("Dr. Who, 2 times!").split_whitespace().collect::<Vec<_>>(), ["doctor", "who,", "two", "times!"]);
+    }
+}